@@ -0,0 +1,111 @@
+// Packing of ascii bits: 64 characters '0' / '1' into one u64, where character i becomes bit i.
+// The characters are validated in the same pass, a word with any other character returns None.
+//
+// There are two implementations:
+//
+//   swar  8 characters at a time in a u64: after xor with '0' every valid byte is 0 or 1, so the
+//         word is valid if no other bit is set, and the low bits of the 8 bytes are gathered into
+//         one byte with a multiplication.
+//   sse2  on x86_64, 16 characters at a time: the bytes are compared with '0' and '1' and the
+//         results are turned into 16-bit masks with _mm_movemask_epi8. SSE2 is part of the
+//         baseline x86_64 target, so no runtime detection is needed.
+
+pub(crate) const ASCII_WORD: usize = 64;
+
+#[inline]
+pub(crate) fn pack_ascii_word(chars: &[u8; ASCII_WORD]) -> Option<u64> {
+    cfg_if::cfg_if! {
+        if #[cfg(target_arch = "x86_64")] {
+            pack_ascii_word_sse2(chars)
+        } else {
+            pack_ascii_word_swar(chars)
+        }
+    }
+}
+
+pub(crate) fn pack_ascii_word_swar(chars: &[u8; ASCII_WORD]) -> Option<u64> {
+    const ZEROS: u64 = 0x3030_3030_3030_3030;
+    const HIGH_BITS: u64 = 0xFEFE_FEFE_FEFE_FEFE;
+    // Byte j of the product has bit 7 - j set, so the low bit of byte i ends up in bit 56 + i.
+    const GATHER: u64 = 0x0102_0408_1020_4080;
+
+    let mut word = 0;
+    let mut invalid = 0;
+    for (i, group) in chars.chunks_exact(8).enumerate() {
+        let bytes = u64::from_le_bytes(group.try_into().unwrap()) ^ ZEROS;
+        invalid |= bytes & HIGH_BITS;
+        word |= (bytes.wrapping_mul(GATHER) >> 56) << (8 * i);
+    }
+    if invalid == 0 { Some(word) } else { None }
+}
+
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn pack_ascii_word_sse2(chars: &[u8; ASCII_WORD]) -> Option<u64> {
+    use core::arch::x86_64::*;
+
+    // Safety: SSE2 is always available on x86_64, and the loads read 16 bytes within `chars`.
+    unsafe {
+        let zeros = _mm_set1_epi8(b'0' as i8);
+        let ones = _mm_set1_epi8(b'1' as i8);
+
+        let mut word = 0;
+        let mut valid = u64::MAX;
+        for i in 0..ASCII_WORD / 16 {
+            let v = _mm_loadu_si128(chars.as_ptr().add(16 * i) as *const __m128i);
+            let is_one = _mm_cmpeq_epi8(v, ones);
+            let is_zero = _mm_cmpeq_epi8(v, zeros);
+            word |= (_mm_movemask_epi8(is_one) as u64) << (16 * i);
+            valid &= !(((_mm_movemask_epi8(_mm_or_si128(is_one, is_zero)) as u64) ^ 0xFFFF) << (16 * i));
+        }
+        if valid == u64::MAX { Some(word) } else { None }
+    }
+}
+
+// Name of the implementation used by pack_ascii_word, for the benchmarks.
+pub fn pack_ascii_impl() -> &'static str {
+    cfg_if::cfg_if! {
+        if #[cfg(target_arch = "x86_64")] {
+            "sse2"
+        } else {
+            "swar"
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand_xoshiro::Xoshiro256Plus;
+
+    fn check(chars: &[u8; ASCII_WORD]) {
+        let expected = if chars.iter().all(|c| *c == b'0' || *c == b'1') {
+            Some(chars.iter().enumerate().map(|(i, c)| ((*c - b'0') as u64) << i).sum::<u64>())
+        } else {
+            None
+        };
+
+        assert_eq!(pack_ascii_word_swar(chars), expected, "swar, {:?}", std::str::from_utf8(chars));
+        assert_eq!(pack_ascii_word(chars), expected, "dispatch, {:?}", std::str::from_utf8(chars));
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(pack_ascii_word_sse2(chars), expected, "sse2, {:?}", std::str::from_utf8(chars));
+    }
+
+    #[test]
+    fn pack_ascii_all_paths() {
+        check(&[b'0'; ASCII_WORD]);
+        check(&[b'1'; ASCII_WORD]);
+
+        let mut rng = Xoshiro256Plus::seed_from_u64(23);
+        for _ in 0..10000 {
+            let mut chars = [0u8; ASCII_WORD];
+            chars.iter_mut().for_each(|c| *c = if rng.gen::<bool>() { b'1' } else { b'0' });
+            check(&chars);
+
+            // One invalid character, including the neighbours of '0' and '1' and bytes >= 0x80.
+            let i = rng.gen_range(0..ASCII_WORD);
+            chars[i] = [b'/', b'2', b'1' + 0x80, b'0' ^ 0x40, b' ', b'\r', b'\n', 0, 0xFF, 0x31 ^ 0x10][rng.gen_range(0..10)];
+            check(&chars);
+        }
+    }
+}
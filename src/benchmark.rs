@@ -1,7 +1,14 @@
 use crate::fast_bvec::*;
+use crate::ascii::*;
+use crate::broadword::select_in_word_impl;
 use crate::bvec::*;
+use crate::popcount::count_ones_impl;
 use crate::tst;
-use crate::tst::ExecQueries;
+use crate::poppy::PoppyVec;
+use crate::rank9::Rank9Vec;
+use crate::rrr::RRRVec;
+use crate::query::{ExecBatch, ExecQueries, Query};
+use crate::measure_time;
 use rand::Rng;
 use seq_macro::seq;
 use prettytable::*;
@@ -10,26 +17,6 @@ use rand_xoshiro::Xoshiro256Plus;
 use rand::seq::SliceRandom;
 use colored::Colorize;
 
-pub struct Params<const A: usize, const B: usize, const C: usize = 1, const SUPERBITS: usize = 40>;
-
-impl<const A: usize, const B: usize, const C: usize, const D: usize> RASBVecParameters for Params<A, B, C, D> {
-    const BLOCK_SIZE: usize = A;
-    const SUPERBLOCK_SIZE: usize = B;
-    const MEGABLOCK_FACTOR: usize = C;
-    const SUPERBLOCK_BITS: usize = D;
-}
-
-#[macro_export]
-macro_rules! measure_time {
-    ($block:block) => {
-        {
-            let start = std::time::Instant::now();
-            $block
-            start.elapsed().as_nanos().div_floor(1000000)
-        }
-    }
-}
-
 trait Benchmarker {
     fn init_benchmark(&mut self, bitlen: usize) -> BitVector {
         BitVector::generate_random(bitlen, 1)
@@ -109,6 +96,10 @@ fn benchmark_generic_random<Bench: Benchmarker>(bitlen: usize, mut b: Bench) {
         table_build.add_row(line_build);
         table_runtime.add_row(line_run);
     }
+    // Layouts without block/superblock parameters, for comparison.
+    add_comparison_row::<Rank9Vec, _>("Rank9", &bits, &b, &mut table_build, &mut table_runtime, M);
+    add_comparison_row::<PoppyVec, _>("Poppy", &bits, &b, &mut table_build, &mut table_runtime, M);
+
     println!("Bit vector space: {:.2} MB", bits.get_memory_usage() as f64 / 1024.0 / 1024.0);
 
     println!("Build times:");
@@ -118,6 +109,31 @@ fn benchmark_generic_random<Bench: Benchmarker>(bitlen: usize, mut b: Bench) {
     table_runtime.printstd();
 }
 
+// Run the benchmark on a structure of type V and add it as a separate row to both tables, padded
+// to `nr_columns` columns.
+fn add_comparison_row<V: RankSelectVector, Bench: Benchmarker>(name: &str, bits: &BitVector, b: &Bench,
+                                                              table_build: &mut Table, table_runtime: &mut Table, nr_columns: usize) {
+    let bv;
+    let bclone = bits.clone();
+    let build_time = measure_time!({
+        bv = V::new(bclone);
+    });
+    let memory = bv.get_memory_usage() + std::mem::size_of::<V>();
+    let run_time = measure_time!({
+        b.run_benchmark(&bv);
+    });
+    println!("Finished {} build={}ms run={}ms", name, build_time, run_time);
+
+    let mut line_build = row![name, format!("{}ms / {:.2} MB", build_time, memory as f64 / 1024.0 / 1024.0)];
+    let mut line_run = row![name, format!("{}ms", run_time)];
+    for _ in 1..nr_columns {
+        line_build.add_cell(Cell::new(""));
+        line_run.add_cell(Cell::new(""));
+    }
+    table_build.add_row(line_build);
+    table_runtime.add_row(line_run);
+}
+
 struct RankBenchmark {
     queries: Vec<usize>
 }
@@ -132,7 +148,6 @@ impl Benchmarker for RankBenchmark {
 }
 
 pub fn benchmark_rank(n: usize, q: usize) {
-    let n = n;
     let mut rng = Xoshiro256Plus::seed_from_u64(123);
 
     let mut bench = RankBenchmark {
@@ -195,8 +210,8 @@ pub fn benchmark_select_megablock_factor(n: usize, queries: usize) {
     const BRUTEFORCE: [usize; 10] = [1, 2, 4, 8, 16, 32, 128, 256, 512, 1024];
     const N: usize = BRUTEFORCE.len();
 
-    let mut runtimes0 = vec![0u128; N];
-    let mut runtimes1 = vec![0u128; N];
+    let mut runtimes0 = [0u128; N];
+    let mut runtimes1 = [0u128; N];
 
     let bv = BitVector::generate_random(n, 4444);
     let queries = generate_random_select_queries(&bv, queries, 111);
@@ -249,10 +264,61 @@ pub fn benchmark_select_megablock_factor(n: usize, queries: usize) {
     }
 
     table.printstd();
+
+    // The same queries with select samples instead of the megablock binary search.
+    const SAMPLE_RATES: [usize; 6] = [256, 1024, 4096, 16384, 65536, 262144];
+    const K: usize = SAMPLE_RATES.len();
+
+    let mut sampled0 = [0u128; K];
+    let mut sampled1 = [0u128; K];
+    let mut memory = [0usize; K];
+
+    seq!(I in 0..6 {
+        {
+            const RATE: usize = SAMPLE_RATES[I];
+
+            let bits = bv.clone();
+            type AccelVector = FastRASBVec<Params<8192, 32768, 32, 40, RATE>>;
+            let bv = AccelVector::new(bits);
+            memory[I] = bv.get_memory_usage();
+
+            sampled1[I] = measure_time!({
+                for (x, t) in &queries {
+                    if *t {
+                        bv.select1(*x);
+                    }
+                }
+            });
+
+            sampled0[I] = measure_time!({
+                for (x, t) in &queries {
+                    if !*t {
+                        bv.select0(*x);
+                    }
+                }
+            });
+
+            println!("Finished sample rate={} run1={}ms run0={}ms", RATE, sampled1[I], sampled0[I]);
+        }
+    });
+
+    let mut table = Table::new();
+    table.add_row(row!["Sample rate", "Run 1", "Run 0", "Total", "Memory"]);
+    for i in 0..SAMPLE_RATES.len() {
+        table.add_row(row![
+            SAMPLE_RATES[i],
+            format!("{:.3}s", sampled1[i] as f64 / 1000.0),
+            format!("{:.3}s", sampled0[i] as f64 / 1000.0),
+            format!("{:.3}s", (sampled1[i] + sampled0[i]) as f64 / 1000.0),
+            format!("{:.2} MB", memory[i] as f64 / 1024.0 / 1024.0)
+        ]);
+    }
+
+    table.printstd();
 }
 
 struct RandomRankSelectBenchmark {
-    queries: Vec<tst::Query>,
+    queries: Vec<Query>,
     nr_queries: usize,
     seed: u64,
 }
@@ -280,17 +346,217 @@ impl Benchmarker for RandomRankSelectBenchmark {
     }
 }
 
+// Run the select queries on `bv`, returns the time for the select1 and for the select0 queries.
+fn run_select_queries<V: RankSelectVector>(bv: &V, queries: &[(usize, bool)]) -> (u128, u128) {
+    let run1 = measure_time!({
+        for (x, t) in queries {
+            if *t {
+                bv.select1(*x);
+            }
+        }
+    });
+
+    let run0 = measure_time!({
+        for (x, t) in queries {
+            if !*t {
+                bv.select0(*x);
+            }
+        }
+    });
+    (run1, run0)
+}
+
+// Compare the select queries with the sorted megablocks against the megablocks in Eytzinger order,
+// for several megablock factors. A small factor gives a large megablock array, which does not fit
+// in the cache.
+pub fn benchmark_select_eytzinger(n: usize, queries: usize) {
+    const FACTORS: [usize; 4] = [1, 4, 32, 256];
+
+    let bv = BitVector::generate_random(n, 4444);
+    let queries = generate_random_select_queries(&bv, queries, 111);
+
+    let mut table = Table::new();
+    table.add_row(row!["MBF", "Sorted", "Eytzinger", "Sorted memory", "Eytzinger memory"]);
+
+    seq!(I in 0..4 {
+        {
+            const MBF: usize = FACTORS[I];
+
+            let sorted = FastRASBVec::<Params<512, 2048, MBF>>::new(bv.clone());
+            let (run1, run0) = run_select_queries(&sorted, &queries);
+            let sorted_memory = sorted.get_memory_usage();
+            drop(sorted);
+
+            let eytzinger = FastRASBVec::<Params<512, 2048, MBF, 40, 0, true>>::new(bv.clone());
+            let (eytzinger_run1, eytzinger_run0) = run_select_queries(&eytzinger, &queries);
+
+            println!("Finished MBF={} sorted={}ms eytzinger={}ms", MBF, run1 + run0, eytzinger_run1 + eytzinger_run0);
+            table.add_row(row![
+                MBF,
+                format!("{:.3}s", (run1 + run0) as f64 / 1000.0),
+                format!("{:.3}s", (eytzinger_run1 + eytzinger_run0) as f64 / 1000.0),
+                format!("{:.2} MB", sorted_memory as f64 / 1024.0 / 1024.0),
+                format!("{:.2} MB", eytzinger.get_memory_usage() as f64 / 1024.0 / 1024.0)
+            ]);
+        }
+    });
+
+    table.printstd();
+}
+
+// Build time of the rank directory with an increasing number of threads, up to the number of CPUs.
+pub fn benchmark_parallel_build(n: usize) {
+    let bits = BitVector::generate_random(n, 5555);
+    let cpus = std::thread::available_parallelism().map_or(1, |x| x.get());
+    let threads = (0..).map(|i| 1 << i).take_while(|t| *t < cpus).chain([cpus]).collect::<Vec<_>>();
+
+    let mut table = Table::new();
+    table.add_row(row!["Threads", "Build", "Speedup"]);
+
+    let mut sequential = 0;
+    for t in threads {
+        let bv;
+        let bclone = bits.clone();
+        let build_time = measure_time!({
+            bv = FastRASBVec::<DefaultParams>::new_parallel(bclone, t);
+        });
+        drop(bv);
+
+        if t == 1 {
+            sequential = build_time;
+        }
+        println!("Finished threads={} build={}ms", t, build_time);
+        table.add_row(row![t, format!("{}ms", build_time), format!("{:.2}x", sequential as f64 / build_time.max(1) as f64)]);
+    }
+
+    table.printstd();
+}
+
+// Compare answering the queries one by one with exec_batch, which prefetches the following queries.
+// Rank and access queries are measured separately from select queries, because nothing is prefetched
+// for select.
+pub fn benchmark_batch_queries(n: usize, nr_queries: usize) {
+    let bits = BitVector::generate_random(n, 6666);
+    let queries = tst::generate_random_queries(nr_queries, 6667, n, Some(bits.rank(n)));
+    let (select, rank): (Vec<Query>, Vec<Query>) = queries.into_iter().partition(|q| matches!(q,
+        Query::Select0(_) | Query::Select1(_) | Query::Next0(_) | Query::Next1(_) | Query::Prev0(_) | Query::Prev1(_) | Query::SelectFrom0(..) | Query::SelectFrom1(..)));
+
+    let mut table = Table::new();
+    table.add_row(row!["Structure", "Queries", "Sequential", "Batch"]);
+
+    fn add_rows<V: RankSelectVector>(name: &str, bv: &V, sets: &[(&str, &[Query])], table: &mut Table) {
+        for (kind, queries) in sets {
+            let sequential = measure_time!({
+                queries.iter().exec_queries(bv).for_each(|a| { std::hint::black_box(a); });
+            });
+            let batch = measure_time!({
+                drop(std::hint::black_box(bv.exec_batch(queries)));
+            });
+            println!("Finished {} {} sequential={}ms batch={}ms", name, kind, sequential, batch);
+            table.add_row(row![name, kind, format!("{}ms", sequential), format!("{}ms", batch)]);
+        }
+    }
+
+    let sets: [(&str, &[Query]); 2] = [("rank/access/count", &rank), ("select/next/prev", &select)];
+    add_rows("FastRASBVec", &FastRASBVec::<DefaultParams>::new(bits.clone()), &sets, &mut table);
+    add_rows("Rank9Vec", &Rank9Vec::new(bits), &sets, &mut table);
+
+    table.printstd();
+}
+
+// Compare the implementations of pack_ascii_word, and reading a whole line of n ascii bits with
+// BitVector::new_from_input.
+pub fn benchmark_parse_bits(n: usize) {
+    let chars = tst::generate_random_bits_string(n, 7777, 0.5).into_bytes();
+    let words = chars.chunks_exact(ASCII_WORD).map(|c| c.try_into().unwrap()).collect::<Vec<&[u8; ASCII_WORD]>>();
+
+    let mut table = Table::new();
+    table.add_row(row!["Implementation", "Time", "Throughput"]);
+    let mut add_row = |name: &str, time: u128| {
+        println!("Finished {} time={}ms", name, time);
+        table.add_row(row![name, format!("{}ms", time), format!("{:.2} GB/s", n as f64 / time.max(1) as f64 / 1e6)]);
+    };
+
+    type PackFn = fn(&[u8; ASCII_WORD]) -> Option<u64>;
+    let mut impls: Vec<(&str, PackFn)> = vec![("swar", pack_ascii_word_swar)];
+    #[cfg(target_arch = "x86_64")]
+    impls.push(("sse2", pack_ascii_word_sse2));
+    for (name, pack) in impls {
+        let time = measure_time!({
+            std::hint::black_box(words.iter().map(|w| pack(std::hint::black_box(w)).unwrap()).fold(0, |a, b| a ^ b));
+        });
+        add_row(name, time);
+    }
+
+    let time = measure_time!({
+        std::hint::black_box(BitVector::new_from_input(&mut chars.as_slice()).unwrap());
+    });
+    add_row(&format!("new_from_input ({})", pack_ascii_impl()), time);
+
+    table.printstd();
+}
+
+// Build one structure on `bits` and run the queries on it. `extra_memory` is added to the memory
+// usage of the structure, for structures which need the original bits.
+// Returns a table row with the build time, the memory usage and the query time.
+fn benchmark_one<V: RankSelectVector>(name: &str, bits: &BitVector, queries: &[Query], extra_memory: usize) -> Row {
+    let bv;
+    let bclone = bits.clone();
+    let build_time = measure_time!({
+        bv = V::new(bclone);
+    });
+
+    let run_time = measure_time!({
+        queries.iter().exec_queries(&bv).for_each(drop);
+    });
+
+    println!("Finished {} build={}ms run={}ms", name, build_time, run_time);
+    row![name, format!("{}ms", build_time), format!("{:.2} MB", (bv.get_memory_usage() + extra_memory) as f64 / 1024.0 / 1024.0), format!("{}ms", run_time)]
+}
+
+// Compare the compressed RRRVec with different block sizes against FastRASBVec, on a bit vector with
+// long zero-heavy sections. Unlike FastRASBVec, RRRVec does not keep the original bits, so its memory
+// usage is the full size of the structure; for FastRASBVec the size of the bits is added.
+pub fn benchmark_compressed(nr_sections: usize, nr_queries: usize) {
+    let sections = [
+        tst::SectionDescription { weight0: 0.99, section_len: 1 << 16, probability: 0.8 },
+        tst::SectionDescription { weight0: 0.5, section_len: 1 << 16, probability: 0.2 },
+    ];
+    let bits = BitVector::new_from_string(&tst::generate_random_bits_in_sections(&sections, nr_sections, 555));
+    let ones = bits.count_ones(0, bits.size());
+    let queries = tst::generate_random_queries(nr_queries, 556, bits.size(), Some(ones));
+
+    let mut table = Table::new();
+    table.add_row(row!["Structure", "Build", "Memory", "Run"]);
+
+    table.add_row(benchmark_one::<FastRASBVec<DefaultParams>>("FastRASBVec", &bits, &queries, bits.get_memory_usage()));
+    table.add_row(benchmark_one::<PoppyVec>("PoppyVec", &bits, &queries, bits.get_memory_usage()));
+    table.add_row(benchmark_one::<RRRVec<15, 32>>("RRRVec<15, 32>", &bits, &queries, 0));
+    table.add_row(benchmark_one::<RRRVec<31, 32>>("RRRVec<31, 32>", &bits, &queries, 0));
+    table.add_row(benchmark_one::<RRRVec<63, 32>>("RRRVec<63, 32>", &bits, &queries, 0));
+    table.add_row(benchmark_one::<RRRVec<63, 128>>("RRRVec<63, 128>", &bits, &queries, 0));
+
+    println!("Bit vector space: {:.2} MB", bits.get_memory_usage() as f64 / 1024.0 / 1024.0);
+    table.printstd();
+}
+
 #[allow(dead_code)]
 pub enum AllBench {
     Random,
     SelectBruteforce,
     SelectGeneral,
     RankGeneral,
+    Compressed,
+    SelectEytzinger,
+    ParallelBuild,
+    BatchQueries,
+    ParseBits,
 }
 
 pub fn benchmark_select_all(list: &[AllBench]) {
     let q = 1 << 23;
     let n = 1usize << 34;
+    println!("Select in word: {}, popcount: {}", select_in_word_impl(), count_ones_impl());
 
     for l in list.iter() {
         match l {
@@ -312,6 +578,26 @@ pub fn benchmark_select_all(list: &[AllBench]) {
                 println!("{}", "Testing rank with random bit vector".blue().bold());
                 benchmark_rank(n, q);
             }
+            AllBench::Compressed => {
+                println!("{}", "Testing compressed bit vectors with skewed bit vector".blue().bold());
+                benchmark_compressed(n >> 16, q);
+            }
+            AllBench::SelectEytzinger => {
+                println!("{}", "Testing select with megablocks in Eytzinger order".blue().bold());
+                benchmark_select_eytzinger(n, q);
+            }
+            AllBench::ParallelBuild => {
+                println!("{}", "Testing parallel construction of the rank directory".blue().bold());
+                benchmark_parallel_build(n);
+            }
+            AllBench::BatchQueries => {
+                println!("{}", "Testing batched queries with prefetching".blue().bold());
+                benchmark_batch_queries(n, q);
+            }
+            AllBench::ParseBits => {
+                println!("{}", "Testing parsing of ascii bit vectors".blue().bold());
+                benchmark_parse_bits(n >> 4);
+            }
 
         }
     }
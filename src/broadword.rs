@@ -0,0 +1,185 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+// Select in a single word: the position of the nth (1-based) set bit of a u64.
+//
+// There are three implementations:
+//
+//   slow       a loop over the bits, only used as a reference in the tests.
+//   broadword  the portable broadword algorithm from Vigna ("Broadword Implementation of Rank/Select
+//              Queries"): the byte-wise popcounts are summed up with one multiplication, the byte
+//              which contains the bit is found with a parallel comparison of all prefix sums, and
+//              the bit in that byte with a lookup table.
+//   pdep       on x86_64 with BMI2: deposit the nth bit of a mask onto the set bits and count the
+//              trailing zeros. This is a single instruction on Intel and AMD since Zen 3, but it is
+//              microcoded (up to ~300 cycles, depending on the word) on older AMD CPUs.
+//
+// The implementation is chosen at runtime on the first call: pdep if the CPU supports BMI2 and is
+// not an AMD CPU older than Zen 3, broadword otherwise.
+
+const L8: u64 = 0x0101_0101_0101_0101;
+const H8: u64 = 0x8080_8080_8080_8080;
+
+// SELECT_IN_BYTE[b * 8 + k] is the position of the (k + 1)'th set bit of the byte b.
+static SELECT_IN_BYTE: [u8; 256 * 8] = {
+    let mut table = [0u8; 256 * 8];
+    let mut b = 0;
+    while b < 256 {
+        let mut k = 0;
+        let mut i = 0;
+        while i < 8 {
+            if (b >> i) & 1 == 1 {
+                table[b * 8 + k] = i as u8;
+                k += 1;
+            }
+            i += 1;
+        }
+        b += 1;
+    }
+    table
+};
+
+// Position of the nth set bit of `word`, caller must ensure that this bit is actually there.
+#[inline]
+pub(crate) fn select_in_word(word: u64, nth: usize) -> usize {
+    debug_assert!(nth >= 1 && nth <= word.count_ones() as usize);
+
+    #[cfg(target_arch = "x86_64")]
+    if use_pdep() {
+        // Safety: use_pdep() only returns true if the CPU supports BMI2.
+        return unsafe { select_in_word_pdep(word, nth) };
+    }
+
+    select_in_word_broadword(word, nth)
+}
+
+pub(crate) fn select_in_word_slow(mut word: u64, mut nth: usize) -> usize {
+    for i in 0..64 {
+        nth -= (word & 1) as usize;
+        if nth == 0 {
+            return i;
+        }
+        word >>= 1;
+    }
+    panic!("Should not be reached!");
+}
+
+pub(crate) fn select_in_word_broadword(word: u64, nth: usize) -> usize {
+    let k = (nth - 1) as u64;
+
+    // Byte i of byte_sums is the number of set bits in bytes 0..=i.
+    let mut s = word - ((word >> 1) & 0x5555_5555_5555_5555);
+    s = (s & 0x3333_3333_3333_3333) + ((s >> 2) & 0x3333_3333_3333_3333);
+    s = (s + (s >> 4)) & 0x0F0F_0F0F_0F0F_0F0F;
+    let byte_sums = s.wrapping_mul(L8);
+
+    // The high bit of byte i is set if byte_sums[i] <= k. The prefix sums are at most 64, so the
+    // subtraction never borrows across bytes. The number of such bytes is the index of the byte
+    // which contains the bit, it is summed up in the top byte and multiplied by 8.
+    let le_k = ((k.wrapping_mul(L8) | H8) - byte_sums) & H8;
+    let place = (((le_k >> 7).wrapping_mul(L8) >> 53) & !7) as usize;
+
+    let rank_in_byte = k as usize - (((byte_sums << 8) >> place) & 0xFF) as usize;
+    place + SELECT_IN_BYTE[((word >> place) & 0xFF) as usize * 8 + rank_in_byte] as usize
+}
+
+/// # Safety
+///
+/// The CPU must support BMI2.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "bmi2")]
+pub(crate) unsafe fn select_in_word_pdep(word: u64, nth: usize) -> usize {
+    use core::arch::x86_64::_pdep_u64;
+    _pdep_u64(1 << (nth - 1), word).trailing_zeros() as usize
+}
+
+const UNKNOWN: u8 = 0;
+const PDEP: u8 = 1;
+const BROADWORD: u8 = 2;
+
+static SELECT_IMPL: AtomicU8 = AtomicU8::new(UNKNOWN);
+
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
+fn use_pdep() -> bool {
+    match SELECT_IMPL.load(Ordering::Relaxed) {
+        PDEP => true,
+        BROADWORD => false,
+        _ => {
+            let pdep = std::is_x86_feature_detected!("bmi2") && !has_slow_pdep();
+            SELECT_IMPL.store(if pdep { PDEP } else { BROADWORD }, Ordering::Relaxed);
+            pdep
+        }
+    }
+}
+
+// AMD CPUs before Zen 3 (family 0x19) implement pdep in microcode.
+#[cfg(target_arch = "x86_64")]
+fn has_slow_pdep() -> bool {
+    use core::arch::x86_64::__cpuid;
+
+    let vendor = __cpuid(0);
+    let is_amd = (vendor.ebx, vendor.edx, vendor.ecx) == (0x6874_7541, 0x6974_6E65, 0x444D_4163); // "AuthenticAMD"
+    if !is_amd {
+        return false;
+    }
+
+    let eax = __cpuid(1).eax;
+    let mut family = (eax >> 8) & 0xF;
+    if family == 0xF {
+        family += (eax >> 20) & 0xFF;
+    }
+    family < 0x19
+}
+
+// Name of the implementation used by select_in_word, for the benchmarks.
+pub fn select_in_word_impl() -> &'static str {
+    cfg_if::cfg_if! {
+        if #[cfg(target_arch = "x86_64")] {
+            if use_pdep() { "pdep" } else { "broadword" }
+        } else {
+            "broadword"
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand_xoshiro::Xoshiro256Plus;
+
+    fn check_word(word: u64) {
+        for nth in 1..=word.count_ones() as usize {
+            let expected = select_in_word_slow(word, nth);
+            assert_eq!(select_in_word_broadword(word, nth), expected, "broadword, word={:#x} nth={}", word, nth);
+            assert_eq!(select_in_word(word, nth), expected, "dispatch, word={:#x} nth={}", word, nth);
+
+            #[cfg(target_arch = "x86_64")]
+            if std::is_x86_feature_detected!("bmi2") {
+                assert_eq!(unsafe { select_in_word_pdep(word, nth) }, expected, "pdep, word={:#x} nth={}", word, nth);
+            }
+        }
+    }
+
+    #[test]
+    fn select_in_word_all_paths() {
+        for word in [0, 1, u64::MAX, 1 << 63, 0x8000_0000_0000_0001, 0xFF00_0000_0000_00FF, 0x5555_5555_5555_5555] {
+            check_word(word);
+        }
+        for i in 0..64 {
+            check_word(1 << i);
+            check_word(!(1u64 << i));
+        }
+
+        let mut rng = Xoshiro256Plus::seed_from_u64(14);
+        for _ in 0..10000 {
+            // Mix dense and sparse words.
+            let word = match rng.gen_range(0..3) {
+                0 => rng.gen::<u64>(),
+                1 => rng.gen::<u64>() & rng.gen::<u64>() & rng.gen::<u64>(),
+                _ => rng.gen::<u64>() | rng.gen::<u64>() | rng.gen::<u64>(),
+            };
+            check_word(word);
+        }
+    }
+}
@@ -1,21 +1,23 @@
-use std::mem::size_of;
 use num::Integer;
-use cfg_if::cfg_if;
 use rand::{Rng, SeedableRng};
 use rand_xoshiro::Xoshiro256Plus;
 
-use std::fs::File;
-use std::io::BufReader;
-use std::io::Read;
-use std::io::BufRead;
+use std::io::{BufRead, Read, Write};
 use std::iter::Iterator;
 
-type BitCell = u64;
-const BIT_CELL_SIZE: usize = size_of::<BitCell>() * 8;
+use crate::broadword::select_in_word;
+use crate::eytzinger::prefetch;
+use crate::popcount::count_ones_words;
+use crate::parser::{read_bits, read_bits_exact, read_bits_hex, read_bits_packed, read_bits_positions, ParseError};
+use crate::query::Query;
+use crate::storage::Storage;
+
+pub(crate) type BitCell = u64;
+pub(crate) const BIT_CELL_SIZE: usize = BitCell::BITS as usize;
 
 #[derive(Clone, Debug)]
 pub struct BitVector {
-    bits: Vec<BitCell>,
+    bits: Storage<BitCell>,
     size: usize,
 }
 
@@ -24,53 +26,167 @@ impl BitVector {
         self.size
     }
 
-    // Read a bit vector in ascii form (0101010011...) from a file until newline is found.
-    pub fn new_from_input(file: &mut BufReader<File>) -> Self {
-        let mut v = vec![];
-        const BUF_SIZE: u64 = 1 << 26;
-        let mut buf = vec![];
-        let mut total_size: usize = 0;
-        let mut idx: usize = 0;
+    // The raw BitCells of the bit vector. Bits past the end of the vector in the last cell are unspecified.
+    pub(crate) fn words(&self) -> &[BitCell] {
+        &self.bits
+    }
 
-        loop {
-            let n = file.by_ref().take(BUF_SIZE).read_until('\n' as u8, &mut buf).unwrap();
-
-            buf.reserve(n);
-            for i in 0..n {
-                if buf[i] == '\n' as u8 {
-                    return Self {
-                        bits: v,
-                        size: total_size,
-                    }
-                }
+    // Whether the bits are used directly from a memory-mapped file.
+    pub fn is_mapped(&self) -> bool {
+        self.bits.is_mapped()
+    }
 
-                if idx % BIT_CELL_SIZE == 0 {
-                    v.push(0);
-                    idx = 0;
-                }
+    // Create a bit vector from its raw BitCells, caller must ensure that there are exactly enough
+    // cells for `size` bits.
+    pub(crate) fn from_words(bits: impl Into<Storage<BitCell>>, size: usize) -> Self {
+        let bits = bits.into();
+        assert_eq!(bits.len(), size.div_ceil(BIT_CELL_SIZE));
+        BitVector {
+            bits,
+            size,
+        }
+    }
 
-                if buf[i] == '1' as u8 {
-                    *v.last_mut().unwrap() |= (1 as BitCell) << idx;
-                }
+    // Read a bit vector in ascii form (0101010011...) until the end of the line (or of the input),
+    // from any buffered reader: a file, stdin, an in-memory buffer, ... The line is read in chunks of
+    // 64 MiB. Characters other than 0 and 1 are an error.
+    pub fn new_from_input<R: BufRead>(reader: &mut R) -> Result<Self, ParseError> {
+        read_bits(reader, 1)
+    }
+
+    // Read exactly `len` bits in ascii form from an unbuffered reader, for sources without a line
+    // ending after the bits.
+    pub fn new_from_reader<R: Read>(reader: &mut R, len: usize) -> Result<Self, ParseError> {
+        read_bits_exact(reader, len, 1)
+    }
 
-                idx += 1;
-                total_size += 1;
+    // Read a bit vector in the hex format "<number of bits> <hex digits>", see parser::BitsFormat.
+    pub fn new_from_hex<R: BufRead>(reader: &mut R) -> Result<Self, ParseError> {
+        read_bits_hex(reader, 1)
+    }
+
+    // Read a bit vector in the packed binary format (little-endian length and words), see
+    // parser::BitsFormat.
+    pub fn new_from_packed<R: Read>(reader: &mut R) -> Result<Self, ParseError> {
+        read_bits_packed(reader, 1)
+    }
+
+    // Read a bit vector from the list "<number of bits> <p1> <p2> ..." of its set bits, see
+    // parser::BitsFormat.
+    pub fn new_from_positions<R: BufRead>(reader: &mut R) -> Result<Self, ParseError> {
+        read_bits_positions(reader, 1)
+    }
+
+    // The writers for the formats which the new_from_* functions read. The text formats end with a
+    // line ending, the packed format does not.
+    pub fn write_ascii<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let mut buf = Vec::with_capacity(1 << 16);
+        for (b, word) in self.bits.iter().enumerate() {
+            let len = (self.size - b * BIT_CELL_SIZE).min(BIT_CELL_SIZE);
+            buf.extend((0..len).map(|i| b'0' + ((word >> i) & 1) as u8));
+            if buf.len() >= 1 << 16 {
+                writer.write_all(&buf)?;
+                buf.clear();
             }
+        }
+        buf.push(b'\n');
+        writer.write_all(&buf)
+    }
+
+    pub fn write_hex<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        const DIGITS: &[u8; 16] = b"0123456789abcdef";
+        let mut buf = format!("{} ", self.size).into_bytes();
+        let nr_digits = self.size.div_ceil(4);
+        for (b, word) in self.bits.iter().enumerate() {
+            let word = word & self.word_mask(b);
+            let len = (nr_digits - b * 16).min(16);
+            buf.extend((0..len).map(|k| DIGITS[((word >> (4 * k)) & 0xF) as usize]));
+            if buf.len() >= 1 << 16 {
+                writer.write_all(&buf)?;
+                buf.clear();
+            }
+        }
+        buf.push(b'\n');
+        writer.write_all(&buf)
+    }
+
+    pub fn write_packed<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let mut buf = Vec::with_capacity(1 << 16);
+        buf.extend((self.size as u64).to_le_bytes());
+        for (b, word) in self.bits.iter().enumerate() {
+            buf.extend((word & self.word_mask(b)).to_le_bytes());
+            if buf.len() >= 1 << 16 {
+                writer.write_all(&buf)?;
+                buf.clear();
+            }
+        }
+        writer.write_all(&buf)
+    }
 
-            buf.clear();
+    pub fn write_positions<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let mut writer = std::io::BufWriter::new(writer);
+        write!(writer, "{}", self.size)?;
+        for p in self.iter_ones() {
+            write!(writer, " {}", p)?;
         }
+        writeln!(writer)?;
+        writer.flush()
+    }
+
+    // Mask of the bits of BitCell b which are in the bit vector.
+    fn word_mask(&self, b: usize) -> BitCell {
+        let len = self.size - b * BIT_CELL_SIZE;
+        if len >= BIT_CELL_SIZE { BitCell::MAX } else { ((1 as BitCell) << len) - 1 }
+    }
+
+    // Append a bit at the end, in amortized O(1).
+    pub fn push(&mut self, bit: u32) {
+        assert!(bit <= 1);
+        let (b, offset) = self.size.div_rem(&BIT_CELL_SIZE);
+        self.bits.with_vec(|v| {
+            if offset == 0 {
+                v.push(0);
+            }
+
+            // Bits past the end of the last BitCell are unspecified, so clear it as well.
+            v[b] = (v[b] & !((1 as BitCell) << offset)) | ((bit as BitCell) << offset);
+        });
+        self.size += 1;
+    }
+
+    // Append the first `len` bits of `words`, a whole BitCell at a time.
+    pub(crate) fn extend_from_words(&mut self, words: &[BitCell], len: usize) {
+        assert!(len <= words.len() * BIT_CELL_SIZE);
+        let offset = self.size % BIT_CELL_SIZE;
+        let size = self.size + len;
+        self.bits.with_vec(|v| {
+            if offset != 0 {
+                // Bits past the end of the last BitCell are unspecified, clear them before or-ing.
+                *v.last_mut().unwrap() &= ((1 as BitCell) << offset) - 1;
+            }
+            for word in &words[..len.div_ceil(BIT_CELL_SIZE)] {
+                if offset == 0 {
+                    v.push(*word);
+                } else {
+                    *v.last_mut().unwrap() |= word << offset;
+                    v.push(word >> (BIT_CELL_SIZE - offset));
+                }
+            }
+            v.truncate(size.div_ceil(BIT_CELL_SIZE));
+        });
+        self.size = size;
     }
 
     pub fn new_from_string(bits: &str) -> Self {
         let mut v = vec![0; bits.len().div_ceil(BIT_CELL_SIZE)];
         let mut bytes = bits.bytes();
         for i in 0..bits.len() {
-            if bytes.next().unwrap() == '1' as u8 {
+            if bytes.next().unwrap() == b'1' {
                 v[i / BIT_CELL_SIZE] |= (1 as BitCell) << (i % BIT_CELL_SIZE);
             }
         }
         BitVector {
-            bits: v,
+            bits: v.into(),
             size: bits.len(),
         }
     }
@@ -84,7 +200,7 @@ impl BitVector {
         }
 
         BitVector {
-            bits,
+            bits: bits.into(),
             size: length,
         }
     }
@@ -95,6 +211,64 @@ impl BitVector {
         return ((self.bits[i / BIT_CELL_SIZE] >> (i % BIT_CELL_SIZE)) & 1) as u32;
     }
 
+    // Get the `len` bits starting at position `start` packed into a BitCell, `start + len` must not
+    // be past the end of the bitvector.
+    pub fn get_bits(&self, start: usize, len: usize) -> BitCell {
+        assert!(len <= BIT_CELL_SIZE && start + len <= self.size);
+        if len == 0 {
+            return 0;
+        }
+
+        let (b, offset) = start.div_rem(&BIT_CELL_SIZE);
+        let mut v = self.bits[b] >> offset;
+        if offset + len > BIT_CELL_SIZE {
+            v |= self.bits[b + 1] << (BIT_CELL_SIZE - offset);
+        }
+
+        if len < BIT_CELL_SIZE {
+            v &= ((1 as BitCell) << len) - 1;
+        }
+        v
+    }
+
+    // Iterate over the positions of all set bits, in increasing order.
+    pub fn iter_ones(&self) -> impl Iterator<Item = usize> + '_ {
+        self.bits.iter().enumerate().flat_map(|(b, bit_cell)| {
+            let mut bit_cell = *bit_cell;
+            std::iter::from_fn(move || {
+                if bit_cell == 0 {
+                    return None;
+                }
+                let i = bit_cell.trailing_zeros() as usize;
+                bit_cell &= bit_cell - 1;
+                Some(b * BIT_CELL_SIZE + i)
+            })
+        }).take_while(|i| *i < self.size)
+    }
+
+    // The first bit with value x at a position >= p in the BitCell which contains p, p < size.
+    pub(crate) fn next_x_in_cell(&self, x: u32, p: usize) -> Option<usize> {
+        let (b, offset) = p.div_rem(&BIT_CELL_SIZE);
+        let cell = if x == 1 { self.bits[b] } else { !self.bits[b] };
+        let matches = cell & (BitCell::MAX << offset);
+        let i = b * BIT_CELL_SIZE + matches.trailing_zeros() as usize;
+        (matches != 0 && i < self.size).then_some(i)
+    }
+
+    // The last bit with value x at a position <= p in the BitCell which contains p, p < size.
+    pub(crate) fn prev_x_in_cell(&self, x: u32, p: usize) -> Option<usize> {
+        let (b, offset) = p.div_rem(&BIT_CELL_SIZE);
+        let cell = if x == 1 { self.bits[b] } else { !self.bits[b] };
+        let matches = cell & (BitCell::MAX >> (BIT_CELL_SIZE - 1 - offset));
+        (matches != 0).then(|| b * BIT_CELL_SIZE + BIT_CELL_SIZE - 1 - matches.leading_zeros() as usize)
+    }
+
+    // Hint the CPU to load the BitCell which contains bit i. i may be past the end.
+    #[inline(always)]
+    pub(crate) fn prefetch_bit(&self, i: usize) {
+        prefetch(self.bits.as_ptr().wrapping_add(i / BIT_CELL_SIZE));
+    }
+
     // Count the number of set bits in [l, r), where [l, r) are indices within a single BitCell.
     fn count_ones_bit_cell(&self, b: usize, l: usize, r: usize) -> usize {
         let mut v = self.bits[b];
@@ -107,6 +281,10 @@ impl BitVector {
 
     // Count the number of ones in [l, r)
     pub fn count_ones(&self, l: usize, r: usize) -> usize {
+        if l >= r {
+            return 0;
+        }
+
         let (mut s_bit_cell, s_offset) = l.div_rem(&BIT_CELL_SIZE);
         let (e_bit_cell, e_offset) = r.div_rem(&BIT_CELL_SIZE);
 
@@ -121,9 +299,7 @@ impl BitVector {
             s_bit_cell += 1;
         }
 
-        for b in s_bit_cell..e_bit_cell {
-            count += self.bits[b].count_ones() as usize;
-        }
+        count += count_ones_words(&self.bits[s_bit_cell..e_bit_cell]);
 
         if e_offset != 0 {
             count += self.count_ones_bit_cell(e_bit_cell, 0, e_offset);
@@ -132,37 +308,9 @@ impl BitVector {
         return count
     }
 
-    fn find_nth_set_bit_slow(&self, mut bit_cell: BitCell, mut nth: usize) -> usize {
-        for i in 0..BIT_CELL_SIZE {
-            nth -= (bit_cell & 1) as usize;
-            if nth == 0 {
-                return i;
-            }
-            bit_cell >>= 1;
-        }
-        panic!("Should not be reached!");
-    }
-
     // Find the n'th set bit in the bit cell, caller must ensure that this bit is actually there.
-    // On x86, uses pdep, on others, uses a naive loop.
     fn find_nth_set_bit(&self, bit_cell: BitCell, nth: usize) -> usize {
-        if BIT_CELL_SIZE != 64 {
-            return self.find_nth_set_bit_slow(bit_cell, nth);
-        }
-
-        cfg_if! {
-            if #[cfg(target_arch = "x86_64")] {
-                use core::arch::x86_64::_pdep_u64;
-                let mask = (1 as BitCell) << (nth - 1);
-                let r: u64 = unsafe {
-                    _pdep_u64(mask, bit_cell)
-                };
-
-                r.trailing_zeros() as usize
-            } else {
-                self.find_nth_set_bit_slow(bit_cell, nth)
-            }
-        }
+        select_in_word(bit_cell, nth)
     }
 
     // Find the number of bits with value x in [l, r) where `[l, r)` is entirely contained within
@@ -216,6 +364,12 @@ impl BitVector {
     }
 }
 
+impl Extend<u32> for BitVector {
+    fn extend<I: IntoIterator<Item = u32>>(&mut self, bits: I) {
+        bits.into_iter().for_each(|bit| self.push(bit));
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -233,6 +387,23 @@ mod tests {
         }
     }
 
+    #[test]
+    fn extend_from_words() {
+        let str = tst::generate_random_bits_string(1000, 3, 0.5);
+        let all = BitVector::new_from_string(&str);
+        for pieces in [[0, 64, 128, 1000], [1, 64, 200, 1000], [63, 63, 700, 1000]] {
+            let mut bv = BitVector::new_from_string("");
+            let mut start = 0;
+            for end in pieces {
+                let piece = BitVector::new_from_string(&str[start..end]);
+                bv.extend_from_words(piece.words(), end - start);
+                start = end;
+            }
+            assert_eq!(bv.size(), 1000);
+            assert!((0..1000).all(|i| bv.get_nth(i) == all.get_nth(i)), "{:?}", pieces);
+        }
+    }
+
     #[test]
     fn test_count_ones() {
         let n = 3*128 + 15;
@@ -274,6 +445,62 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn next_prev_select_from() {
+        for (n, weight0) in [(3*128 + 15, 0.5), (128, 0.9), (70, 0.0), (65, 1.0)] {
+            let bv = BitVector::new_from_string(&tst::generate_random_bits_string(n, 1, weight0));
+            let positions = |x: u32| (0..n).filter(|i| bv.get_nth(*i) == x).collect::<Vec<_>>();
+            for x in [0, 1] {
+                let positions = positions(x);
+                for p in 0..=n {
+                    let after = positions.iter().copied().filter(|i| *i >= p).collect::<Vec<_>>();
+                    assert_eq!(bv.next(x, p), after.first().copied(), "next({}, {}) n = {}", x, p, n);
+                    assert_eq!(bv.select_from(x, p, 0), None);
+                    assert_eq!(bv.select_from(x, p, 3), after.get(2).copied(), "select_from({}, {}, 3) n = {}", x, p, n);
+                    if p < n {
+                        assert_eq!(bv.prev(x, p), positions.iter().copied().rev().find(|i| *i <= p), "prev({}, {}) n = {}", x, p, n);
+                    }
+                    assert_eq!(bv.count_range(p / 2, p), bv.rank(p) - bv.rank(p / 2));
+                }
+            }
+        }
+    }
+
+    // A reader which returns at most 3 bytes per read, like a slow socket.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(3);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn new_from_readers() {
+        let str = tst::generate_random_bits_string(1000, 7, 0.5);
+        let expected = BitVector::new_from_string(&str);
+        let check = |bv: BitVector| {
+            assert_eq!(bv.size(), expected.size());
+            assert!((0..bv.size()).all(|i| bv.get_nth(i) == expected.get_nth(i)));
+        };
+
+        let text = format!("{}\nrank 1 5\n", str);
+        check(BitVector::new_from_input(&mut text.as_bytes()).unwrap());
+        check(BitVector::new_from_input(&mut std::io::BufReader::with_capacity(7, Trickle(text.as_bytes()))).unwrap());
+        check(BitVector::new_from_input(&mut std::io::Cursor::new(str.clone())).unwrap());
+
+        // Only the given number of bits is read.
+        let mut reader = Trickle(text.as_bytes());
+        check(BitVector::new_from_reader(&mut reader, 1000).unwrap());
+        assert_eq!(reader.0, b"\nrank 1 5\n");
+
+        assert!(BitVector::new_from_reader(&mut Trickle(str.as_bytes()), 1001).is_err());
+        assert_eq!(BitVector::new_from_reader(&mut Trickle(b""), 0).unwrap().size(), 0);
+    }
 }
 
 pub trait RankSelectVector {
@@ -294,6 +521,49 @@ pub trait RankSelectVector {
 
     // Get the memory usage for the bit vector
     fn get_memory_usage(&self) -> usize;
+
+    // Hint the CPU to load the memory which answering `q` reads, see query::ExecBatch. This is only a
+    // hint, the default does nothing.
+    #[inline(always)]
+    fn prefetch(&self, _q: &Query) {}
+
+    // Return the number of 1s on positions [l, ... r).
+    fn count_range(&self, l: usize, r: usize) -> usize {
+        assert!(l <= r);
+        self.rank(r) - self.rank(l)
+    }
+
+    // Get the position of the first bit with value x at a position >= p, p <= size.
+    fn next(&self, x: u32, p: usize) -> Option<usize> {
+        self.select_from(x, p, 1)
+    }
+
+    // Get the position of the last bit with value x at a position <= p, p < size.
+    fn prev(&self, x: u32, p: usize) -> Option<usize> {
+        match rank_x(self, x, p + 1) {
+            0 => None,
+            r => select_x(self, x, r),
+        }
+    }
+
+    // Get the position of the k'th bit with value x at a position >= p, p <= size.
+    fn select_from(&self, x: u32, p: usize, k: usize) -> Option<usize> {
+        if k == 0 {
+            return None;
+        }
+        select_x(self, x, rank_x(self, x, p) + k)
+    }
+}
+
+// Number of bits with value x on positions [0, ... i).
+pub(crate) fn rank_x<V: RankSelectVector + ?Sized>(b: &V, x: u32, i: usize) -> usize {
+    let r = b.rank(i);
+    if x == 1 { r } else { i - r }
+}
+
+// Position of the i'th bit with value x.
+pub(crate) fn select_x<V: RankSelectVector + ?Sized>(b: &V, x: u32, i: usize) -> Option<usize> {
+    if x == 1 { b.select1(i) } else { b.select0(i) }
 }
 
 impl RankSelectVector for BitVector {
@@ -324,9 +594,38 @@ impl RankSelectVector for BitVector {
         self.get_nth(i)
     }
 
+    fn prefetch(&self, q: &Query) {
+        if let Query::Access(i) = *q {
+            self.prefetch_bit(i);
+        }
+    }
+
     fn get_memory_usage(&self) -> usize {
         return self.bits.len() * std::mem::size_of::<BitCell>();
     }
+
+    // Like rank and select, these scan the bits.
+    fn count_range(&self, l: usize, r: usize) -> usize {
+        assert!(l <= r && r <= self.size);
+        self.count_ones(l, r)
+    }
+
+    fn next(&self, x: u32, p: usize) -> Option<usize> {
+        self.select_from(x, p, 1)
+    }
+
+    fn prev(&self, x: u32, p: usize) -> Option<usize> {
+        assert!(p < self.size);
+        (0..=p).rev().find(|i| self.get_nth(*i) == x)
+    }
+
+    fn select_from(&self, x: u32, p: usize, k: usize) -> Option<usize> {
+        assert!(p <= self.size);
+        if p == self.size {
+            return None;
+        }
+        self.find_nth_x(p, k, x)
+    }
 }
 
 #[cfg(test)]
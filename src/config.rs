@@ -0,0 +1,240 @@
+use crate::fast_bvec::{Block, RASBVecLayout, RASBVecParameters};
+use std::fmt;
+use std::str::FromStr;
+
+// Layout of a RuntimeRASBVec which is chosen at runtime (for example from the command line or from
+// a config file) instead of through a RASBVecParameters type.
+//
+// A RankSelectConfig is always valid: it can only be created through `new` (or parsed), which checks
+// that the sizes are consistent. The derived values (bits per block counter, bytes per RankSuperblock)
+// are computed once here, because they are needed on every query.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RankSelectConfig {
+    block_size: usize,
+    superblock_size: usize,
+    megablock_factor: usize,
+    superblock_bits: usize,
+    // 0 if select samples are disabled.
+    select_sample_rate: usize,
+    eytzinger_megablocks: bool,
+
+    block_bits: usize,
+    cacheline_size: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    // One of the sizes is zero.
+    ZeroSize,
+    // The superblock size is not a multiple of the block size.
+    SuperblockNotMultipleOfBlock,
+    // The number of bits in a superblock does not fit into the block counters.
+    SuperblockTooBig,
+    // The superblock counters must have between 1 and 64 bits.
+    InvalidSuperblockBits,
+    // The bit vector has more bits than can be counted with the superblock counters.
+    TooManyBits { size: usize, superblock_bits: usize },
+    // The textual description of the config could not be parsed.
+    Parse(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::ZeroSize => write!(f, "block size, superblock size and megablock factor must be positive"),
+            ConfigError::SuperblockNotMultipleOfBlock => write!(f, "superblock size must be a multiple of the block size"),
+            ConfigError::SuperblockTooBig => write!(f, "superblock size is too big for the block counters"),
+            ConfigError::InvalidSuperblockBits => write!(f, "superblock bits must be between 1 and 64"),
+            ConfigError::TooManyBits { size, superblock_bits } =>
+                write!(f, "a bit vector with {} bits cannot be indexed with {} superblock bits", size, superblock_bits),
+            ConfigError::Parse(reason) => write!(f, "invalid config: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl RankSelectConfig {
+    pub fn new(block_size: usize, superblock_size: usize, megablock_factor: usize, superblock_bits: usize) -> Result<Self, ConfigError> {
+        if block_size == 0 || superblock_size == 0 || megablock_factor == 0 {
+            return Err(ConfigError::ZeroSize);
+        }
+
+        if !superblock_size.is_multiple_of(block_size) {
+            return Err(ConfigError::SuperblockNotMultipleOfBlock);
+        }
+
+        if (superblock_size / block_size - 1) * block_size > Block::MAX as usize {
+            return Err(ConfigError::SuperblockTooBig);
+        }
+
+        if superblock_bits == 0 || superblock_bits > 64 {
+            return Err(ConfigError::InvalidSuperblockBits);
+        }
+
+        let block_bits = (64 - superblock_size.leading_zeros()) as usize;
+        Ok(RankSelectConfig {
+            block_size,
+            superblock_size,
+            megablock_factor,
+            superblock_bits,
+            select_sample_rate: 0,
+            eytzinger_megablocks: false,
+            block_bits,
+            cacheline_size: (superblock_bits + (superblock_size / block_size) * block_bits).div_ceil(8),
+        })
+    }
+
+    // The config which corresponds to a compile-time parameter set.
+    pub fn of<Parameters: RASBVecParameters>() -> Result<Self, ConfigError> {
+        Self::new(Parameters::BLOCK_SIZE, Parameters::SUPERBLOCK_SIZE, Parameters::MEGABLOCK_FACTOR, Parameters::SUPERBLOCK_BITS)
+            .map(|config| config.with_select_sample_rate(Parameters::SELECT_SAMPLE_RATE)
+                .with_eytzinger_megablocks(Parameters::EYTZINGER_MEGABLOCKS))
+    }
+
+    // The same config with select samples for every `rate`-th one and zero (0 disables them).
+    pub fn with_select_sample_rate(mut self, rate: usize) -> Self {
+        self.select_sample_rate = rate;
+        self
+    }
+
+    // The same config with the megablocks (also) stored in Eytzinger order for select.
+    pub fn with_eytzinger_megablocks(mut self, eytzinger: bool) -> Self {
+        self.eytzinger_megablocks = eytzinger;
+        self
+    }
+
+    // Check that a bit vector with `size` bits can be indexed with this config.
+    pub fn check_size(&self, size: usize) -> Result<(), ConfigError> {
+        if self.superblock_bits < 64 && size >= (1usize << self.superblock_bits) {
+            return Err(ConfigError::TooManyBits { size, superblock_bits: self.superblock_bits });
+        }
+        Ok(())
+    }
+}
+
+impl Default for RankSelectConfig {
+    // Same layout as DefaultParams.
+    fn default() -> Self {
+        RankSelectConfig::new(4096, 32768, 32, 48).unwrap()
+    }
+}
+
+impl RASBVecLayout for RankSelectConfig {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn superblock_size(&self) -> usize {
+        self.superblock_size
+    }
+
+    fn megablock_factor(&self) -> usize {
+        self.megablock_factor
+    }
+
+    fn superblock_bits(&self) -> usize {
+        self.superblock_bits
+    }
+
+    fn block_bits(&self) -> usize {
+        self.block_bits
+    }
+
+    fn cacheline_size(&self) -> usize {
+        self.cacheline_size
+    }
+
+    fn select_sample_rate(&self) -> usize {
+        self.select_sample_rate
+    }
+
+    fn eytzinger_megablocks(&self) -> bool {
+        self.eytzinger_megablocks
+    }
+
+    fn from_values(block_size: usize, superblock_size: usize, megablock_factor: usize, superblock_bits: usize,
+                   select_sample_rate: usize, eytzinger_megablocks: bool) -> Option<Self> {
+        RankSelectConfig::new(block_size, superblock_size, megablock_factor, superblock_bits).ok()
+            .map(|config| config.with_select_sample_rate(select_sample_rate).with_eytzinger_megablocks(eytzinger_megablocks))
+    }
+}
+
+// Parse a config of the form `block_size=4096, superblock_size=32768, megablock_factor=32, superblock_bits=48`,
+// optionally with `select_sample_rate=...` and `eytzinger_megablocks=1` (0 or 1).
+// Entries may also be separated by newlines, so that a config file can contain one `key = value` per
+// line. Lines starting with `#` are ignored, and missing entries take their value from the default
+// config.
+impl FromStr for RankSelectConfig {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const KEYS: [&str; 6] = ["block_size", "superblock_size", "megablock_factor", "superblock_bits", "select_sample_rate",
+            "eytzinger_megablocks"];
+        let default = RankSelectConfig::default();
+        let mut values = [default.block_size, default.superblock_size, default.megablock_factor, default.superblock_bits,
+            default.select_sample_rate, default.eytzinger_megablocks as usize];
+
+        let entries = s.lines()
+            .filter(|line| !line.trim_start().starts_with('#'))
+            .flat_map(|line| line.split(','))
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty());
+
+        for entry in entries {
+            let Some((key, value)) = entry.split_once('=') else {
+                return Err(ConfigError::Parse(format!("expected `key=value`, got `{}`", entry)));
+            };
+
+            let (key, value) = (key.trim(), value.trim());
+            let idx = KEYS.iter().position(|k| *k == key)
+                .ok_or_else(|| ConfigError::Parse(format!("unknown key `{}`", key)))?;
+            values[idx] = value.parse::<usize>()
+                .map_err(|_| ConfigError::Parse(format!("value `{}` for `{}` is not a number", value, key)))?;
+        }
+
+        if values[5] > 1 {
+            return Err(ConfigError::Parse(format!("value `{}` for `eytzinger_megablocks` must be 0 or 1", values[5])));
+        }
+
+        RankSelectConfig::new(values[0], values[1], values[2], values[3])
+            .map(|config| config.with_select_sample_rate(values[4]).with_eytzinger_megablocks(values[5] == 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let config = "block_size=256, superblock_size = 1024".parse::<RankSelectConfig>().unwrap();
+        assert_eq!(config, RankSelectConfig::new(256, 1024, 32, 48).unwrap());
+
+        let file = "# layout for small vectors\nblock_size = 4\nsuperblock_size = 8\nmegablock_factor = 2\nsuperblock_bits = 20\n";
+        assert_eq!(file.parse::<RankSelectConfig>().unwrap(), RankSelectConfig::new(4, 8, 2, 20).unwrap());
+
+        let sampled = "block_size=256, superblock_size=1024, select_sample_rate=512".parse::<RankSelectConfig>().unwrap();
+        assert_eq!(sampled, RankSelectConfig::new(256, 1024, 32, 48).unwrap().with_select_sample_rate(512));
+
+        let eytzinger = "megablock_factor=4\neytzinger_megablocks = 1".parse::<RankSelectConfig>().unwrap();
+        assert_eq!(eytzinger, RankSelectConfig::new(4096, 32768, 4, 48).unwrap().with_eytzinger_megablocks(true));
+        assert!(matches!("eytzinger_megablocks=2".parse::<RankSelectConfig>(), Err(ConfigError::Parse(_))));
+
+        assert!(matches!("block_size".parse::<RankSelectConfig>(), Err(ConfigError::Parse(_))));
+        assert!(matches!("blocksize=4".parse::<RankSelectConfig>(), Err(ConfigError::Parse(_))));
+        assert!(matches!("block_size=x".parse::<RankSelectConfig>(), Err(ConfigError::Parse(_))));
+    }
+
+    #[test]
+    fn validate() {
+        assert_eq!(RankSelectConfig::new(0, 8, 1, 40), Err(ConfigError::ZeroSize));
+        assert_eq!(RankSelectConfig::new(3, 8, 1, 40), Err(ConfigError::SuperblockNotMultipleOfBlock));
+        assert_eq!(RankSelectConfig::new(1, 1 << 33, 1, 40), Err(ConfigError::SuperblockTooBig));
+        assert_eq!(RankSelectConfig::new(4, 8, 1, 65), Err(ConfigError::InvalidSuperblockBits));
+
+        let config = RankSelectConfig::of::<crate::fast_bvec::BigRASB>().unwrap();
+        assert_eq!(config.cacheline_size(), crate::fast_bvec::BigRASB::CACHELINE_SIZE);
+        assert_eq!(config.block_bits(), crate::fast_bvec::BigRASB::BLOCK_BITS);
+    }
+}
@@ -0,0 +1,675 @@
+use crate::broadword::select_in_word;
+use crate::popcount::count_ones_words;
+use crate::bvec::{BitCell, BitVector, RankSelectVector, BIT_CELL_SIZE};
+
+// A bit vector which supports inserting, removing and changing bits in O(log n), together with
+// rank, select and access queries in O(log n).
+//
+// The bits are split into chunks of at most LEAF_BITS bits. The chunks are the nodes of an AVL tree,
+// ordered by their position in the bit vector (in-order). Every node stores the number of bits and
+// set bits in its subtree, so queries can walk down from the root and skip whole subtrees.
+//
+// A chunk which becomes full is split in two halves, a chunk which becomes empty is removed from
+// the tree. A chunk which drops below half of LEAF_BITS when removing is merged with a neighbour (or
+// the bits of both are split evenly if they do not fit into one chunk), so all chunks except the
+// ones created by `new` stay at least half full and the tree does not degrade into tiny chunks.
+pub struct DynamicBitVector {
+    root: Tree,
+}
+
+const LEAF_WORDS: usize = 8;
+const LEAF_BITS: usize = LEAF_WORDS * BIT_CELL_SIZE;
+
+type Tree = Option<Box<Node>>;
+
+struct Node {
+    // Bits after `len` are always zero.
+    bits: [BitCell; LEAF_WORDS],
+    len: usize,
+    ones: usize,
+    // Number of bits and set bits in the whole subtree, including this chunk.
+    size: usize,
+    count1: usize,
+    height: u32,
+    left: Tree,
+    right: Tree,
+}
+
+fn size(t: &Tree) -> usize {
+    t.as_ref().map_or(0, |n| n.size)
+}
+
+fn count1(t: &Tree) -> usize {
+    t.as_ref().map_or(0, |n| n.count1)
+}
+
+fn height(t: &Tree) -> u32 {
+    t.as_ref().map_or(0, |n| n.height)
+}
+
+// Number of bits with value `x` in the subtree.
+fn count_x(t: &Tree, x: u32) -> usize {
+    if x == 1 { count1(t) } else { size(t) - count1(t) }
+}
+
+impl Node {
+    fn new(bits: [BitCell; LEAF_WORDS], len: usize) -> Box<Node> {
+        let ones = count_ones_words(&bits);
+        Box::new(Node { bits, len, ones, size: len, count1: ones, height: 1, left: None, right: None })
+    }
+
+    // A chunk with the `len` bits of `bits` at positions [start, start + len).
+    fn from_bits(bits: &BitVector, start: usize, len: usize) -> Box<Node> {
+        assert!(len <= LEAF_BITS);
+        let mut words = [0; LEAF_WORDS];
+        for (w, word) in words.iter_mut().enumerate().take(len.div_ceil(BIT_CELL_SIZE)) {
+            let offset = w * BIT_CELL_SIZE;
+            *word = bits.get_bits(start + offset, BIT_CELL_SIZE.min(len - offset));
+        }
+        Node::new(words, len)
+    }
+
+    fn update(&mut self) {
+        self.size = size(&self.left) + self.len + size(&self.right);
+        self.count1 = count1(&self.left) + self.ones + count1(&self.right);
+        self.height = 1 + height(&self.left).max(height(&self.right));
+    }
+
+    fn get(&self, i: usize) -> u32 {
+        ((self.bits[i / BIT_CELL_SIZE] >> (i % BIT_CELL_SIZE)) & 1) as u32
+    }
+
+    // Number of set bits in the chunk before position i.
+    fn chunk_rank(&self, i: usize) -> usize {
+        let (w, off) = (i / BIT_CELL_SIZE, i % BIT_CELL_SIZE);
+        let mut r = count_ones_words(&self.bits[..w]);
+        if off > 0 {
+            r += (self.bits[w] & (((1 as BitCell) << off) - 1)).count_ones() as usize;
+        }
+        r
+    }
+
+    // Position of the nth bit with value x in the chunk, which must exist.
+    fn chunk_select(&self, mut nth: usize, x: u32) -> usize {
+        for (w, bits) in self.bits.iter().enumerate() {
+            let b = if x == 1 { *bits } else { !*bits };
+            let count = b.count_ones() as usize;
+            if nth <= count {
+                return w * BIT_CELL_SIZE + select_in_word(b, nth);
+            }
+            nth -= count;
+        }
+        panic!("Should not be reached!");
+    }
+
+    // Insert `bit` at position i of the chunk, which must not be full.
+    fn chunk_insert(&mut self, i: usize, bit: u32) {
+        let (w, off) = (i / BIT_CELL_SIZE, i % BIT_CELL_SIZE);
+        for k in (w + 1..LEAF_WORDS).rev() {
+            self.bits[k] = (self.bits[k] << 1) | (self.bits[k - 1] >> (BIT_CELL_SIZE - 1));
+        }
+
+        let low_mask = ((1 as BitCell) << off) - 1;
+        let cell = self.bits[w];
+        self.bits[w] = (cell & low_mask) | ((cell & !low_mask) << 1) | ((bit as BitCell) << off);
+
+        self.len += 1;
+        self.ones += bit as usize;
+    }
+
+    // Remove the bit at position i of the chunk and return it.
+    fn chunk_remove(&mut self, i: usize) -> u32 {
+        let (w, off) = (i / BIT_CELL_SIZE, i % BIT_CELL_SIZE);
+        let bit = self.get(i);
+
+        let low_mask = ((1 as BitCell) << off) - 1;
+        let cell = self.bits[w];
+        self.bits[w] = (cell & low_mask) | ((cell >> 1) & !low_mask);
+        for k in w..LEAF_WORDS - 1 {
+            self.bits[k] |= self.bits[k + 1] << (BIT_CELL_SIZE - 1);
+            self.bits[k + 1] >>= 1;
+        }
+
+        self.len -= 1;
+        self.ones -= bit as usize;
+        bit
+    }
+
+    // Move the upper half of a full chunk into a new node.
+    fn split(&mut self) -> Box<Node> {
+        assert_eq!(self.len, LEAF_BITS);
+        let mut upper = [0; LEAF_WORDS];
+        upper[..LEAF_WORDS / 2].copy_from_slice(&self.bits[LEAF_WORDS / 2..]);
+        self.bits[LEAF_WORDS / 2..].fill(0);
+
+        let node = Node::new(upper, LEAF_BITS / 2);
+        self.len -= node.len;
+        self.ones -= node.ones;
+        node
+    }
+}
+
+fn rotate_right(mut n: Box<Node>) -> Box<Node> {
+    let mut l = n.left.take().unwrap();
+    n.left = l.right.take();
+    n.update();
+    l.right = Some(n);
+    l.update();
+    l
+}
+
+fn rotate_left(mut n: Box<Node>) -> Box<Node> {
+    let mut r = n.right.take().unwrap();
+    n.right = r.left.take();
+    n.update();
+    r.left = Some(n);
+    r.update();
+    r
+}
+
+// Update the node after one of its subtrees changed, and restore the AVL invariant.
+fn rebalance(mut n: Box<Node>) -> Box<Node> {
+    n.update();
+    let (hl, hr) = (height(&n.left), height(&n.right));
+    if hl > hr + 1 {
+        let l = n.left.take().unwrap();
+        n.left = Some(if height(&l.left) < height(&l.right) { rotate_left(l) } else { l });
+        return rotate_right(n);
+    }
+
+    if hr > hl + 1 {
+        let r = n.right.take().unwrap();
+        n.right = Some(if height(&r.right) < height(&r.left) { rotate_right(r) } else { r });
+        return rotate_left(n);
+    }
+
+    n
+}
+
+// Insert `node` before all other chunks of the tree.
+fn insert_min(t: Tree, node: Box<Node>) -> Box<Node> {
+    match t {
+        None => node,
+        Some(mut n) => {
+            n.left = Some(insert_min(n.left.take(), node));
+            rebalance(n)
+        }
+    }
+}
+
+// Remove the first chunk of the tree. Returns the rest of the tree and the removed chunk.
+fn remove_min(mut n: Box<Node>) -> (Tree, Box<Node>) {
+    match n.left.take() {
+        None => (n.right.take(), n),
+        Some(l) => {
+            let (l, min) = remove_min(l);
+            n.left = l;
+            (Some(rebalance(n)), min)
+        }
+    }
+}
+
+// Remove the node `n` from its subtree, returns the rest of the subtree and the node without children.
+fn unlink(mut n: Box<Node>) -> (Tree, Box<Node>) {
+    let t = match (n.left.take(), n.right.take()) {
+        (None, r) => r,
+        (l, None) => l,
+        (Some(l), Some(r)) => {
+            let (r, mut min) = remove_min(r);
+            min.left = Some(l);
+            min.right = r;
+            Some(rebalance(min))
+        }
+    };
+    n.update();
+    (t, n)
+}
+
+// Position of the first bit and length of the chunk which contains position i < size.
+fn chunk_at(mut t: &Tree, mut i: usize) -> (usize, usize) {
+    let mut start = 0;
+    while let Some(n) = t {
+        let ls = size(&n.left);
+        if i < ls {
+            t = &n.left;
+        } else if i < ls + n.len {
+            return (start + ls, n.len);
+        } else {
+            start += ls + n.len;
+            i -= ls + n.len;
+            t = &n.right;
+        }
+    }
+    panic!("Should not be reached!");
+}
+
+// Remove the chunk which starts at position `start`. Returns the rest of the tree and the chunk.
+fn remove_chunk(mut n: Box<Node>, start: usize) -> (Tree, Box<Node>) {
+    let ls = size(&n.left);
+    let chunk;
+    if start < ls {
+        let l;
+        (l, chunk) = remove_chunk(n.left.take().unwrap(), start);
+        n.left = l;
+    } else if start == ls {
+        return unlink(n);
+    } else {
+        let r;
+        (r, chunk) = remove_chunk(n.right.take().unwrap(), start - ls - n.len);
+        n.right = r;
+    }
+    (Some(rebalance(n)), chunk)
+}
+
+// Insert `node` as a new chunk starting at position `start`, which must be the start of a chunk or
+// the end of the tree.
+fn insert_chunk(t: Tree, start: usize, node: Box<Node>) -> Box<Node> {
+    let Some(mut n) = t else {
+        return node;
+    };
+
+    let ls = size(&n.left);
+    if start <= ls {
+        n.left = Some(insert_chunk(n.left.take(), start, node));
+    } else {
+        n.right = Some(insert_chunk(n.right.take(), start - ls - n.len, node));
+    }
+    rebalance(n)
+}
+
+fn insert(t: Tree, i: usize, bit: u32) -> Box<Node> {
+    let Some(mut n) = t else {
+        let mut node = Node::new([0; LEAF_WORDS], 0);
+        node.chunk_insert(0, bit);
+        node.update();
+        return node;
+    };
+
+    let ls = size(&n.left);
+    if i < ls {
+        n.left = Some(insert(n.left.take(), i, bit));
+    } else if i <= ls + n.len {
+        let mut i = i - ls;
+        if n.len == LEAF_BITS {
+            let mut upper = n.split();
+            if i > n.len {
+                i -= n.len;
+                upper.chunk_insert(i, bit);
+                upper.update();
+                n.right = Some(insert_min(n.right.take(), upper));
+                return rebalance(n);
+            }
+            n.right = Some(insert_min(n.right.take(), upper));
+        }
+        n.chunk_insert(i, bit);
+    } else {
+        n.right = Some(insert(n.right.take(), i - ls - n.len, bit));
+    }
+    rebalance(n)
+}
+
+// Remove the bit at position i, returns the rest of the tree and the removed bit.
+fn remove(mut n: Box<Node>, i: usize) -> (Tree, u32) {
+    let ls = size(&n.left);
+    let bit;
+    if i < ls {
+        let l;
+        (l, bit) = remove(n.left.take().unwrap(), i);
+        n.left = l;
+    } else if i < ls + n.len {
+        bit = n.chunk_remove(i - ls);
+        if n.len == 0 {
+            return (unlink(n).0, bit);
+        }
+    } else {
+        let r;
+        (r, bit) = remove(n.right.take().unwrap(), i - ls - n.len);
+        n.right = r;
+    }
+    (Some(rebalance(n)), bit)
+}
+
+// Set the bit at position i, returns the previous value.
+fn set(n: &mut Node, i: usize, bit: u32) -> u32 {
+    let ls = size(&n.left);
+    let old = if i < ls {
+        set(n.left.as_mut().unwrap(), i, bit)
+    } else if i < ls + n.len {
+        let i = i - ls;
+        let old = n.get(i);
+        let mask = (1 as BitCell) << (i % BIT_CELL_SIZE);
+        if bit == 1 {
+            n.bits[i / BIT_CELL_SIZE] |= mask;
+        } else {
+            n.bits[i / BIT_CELL_SIZE] &= !mask;
+        }
+        n.ones = n.ones + bit as usize - old as usize;
+        old
+    } else {
+        set(n.right.as_mut().unwrap(), i - ls - n.len, bit)
+    };
+
+    n.update();
+    old
+}
+
+// Build a balanced tree from consecutive chunks.
+fn build(chunks: &mut [Option<Box<Node>>]) -> Tree {
+    if chunks.is_empty() {
+        return None;
+    }
+
+    let mid = chunks.len() / 2;
+    let (left, rest) = chunks.split_at_mut(mid);
+    let (node, right) = rest.split_first_mut().unwrap();
+    let mut n = node.take().unwrap();
+    n.left = build(left);
+    n.right = build(right);
+    n.update();
+    Some(n)
+}
+
+impl DynamicBitVector {
+    pub fn new_empty() -> Self {
+        DynamicBitVector { root: None }
+    }
+
+    pub fn size(&self) -> usize {
+        size(&self.root)
+    }
+
+    pub fn count_ones(&self) -> usize {
+        count1(&self.root)
+    }
+
+    // Insert `bit` before position i, i == size() appends it.
+    pub fn insert(&mut self, i: usize, bit: u32) {
+        assert!(i <= self.size() && bit <= 1);
+        self.root = Some(insert(self.root.take(), i, bit));
+    }
+
+    pub fn push(&mut self, bit: u32) {
+        self.insert(self.size(), bit);
+    }
+
+    // Remove the bit at position i and return it.
+    pub fn remove(&mut self, i: usize) -> u32 {
+        assert!(i < self.size());
+        let (start, len) = chunk_at(&self.root, i);
+        let bit;
+        (self.root, bit) = remove(self.root.take().unwrap(), i);
+        // An empty chunk was already removed.
+        if len - 1 > 0 && len - 1 < LEAF_BITS / 2 {
+            self.merge_chunk(start, len - 1);
+        }
+        bit
+    }
+
+    // Merge the chunk at [start, start + len) with the next chunk, or with the previous one if it is
+    // the last. If the bits do not fit into one chunk, they are split evenly into two chunks which
+    // are then both at least half full. O(log n).
+    fn merge_chunk(&mut self, start: usize, len: usize) {
+        let first = if start + len < self.size() {
+            start
+        } else if start > 0 {
+            chunk_at(&self.root, start - 1).0
+        } else {
+            return;
+        };
+
+        // The second chunk starts at `first` once the first one is removed.
+        let (root, a) = remove_chunk(self.root.take().unwrap(), first);
+        let (root, b) = remove_chunk(root.unwrap(), first);
+        let mut bits = BitVector::new_from_string("");
+        bits.extend_from_words(&a.bits, a.len);
+        bits.extend_from_words(&b.bits, b.len);
+
+        let total = bits.size();
+        let half = if total <= LEAF_BITS { total } else { total / 2 };
+        let mut root = insert_chunk(root, first, Node::from_bits(&bits, 0, half));
+        if half < total {
+            root = insert_chunk(Some(root), first + half, Node::from_bits(&bits, half, total - half));
+        }
+        self.root = Some(root);
+    }
+
+    // Change the bit at position i, returns the previous value.
+    pub fn set(&mut self, i: usize, bit: u32) -> u32 {
+        assert!(i < self.size() && bit <= 1);
+        set(self.root.as_mut().unwrap(), i, bit)
+    }
+
+    // Invert the bit at position i, returns the new value.
+    pub fn flip(&mut self, i: usize) -> u32 {
+        let bit = 1 - self.access(i);
+        self.set(i, bit);
+        bit
+    }
+
+    fn generic_select(&self, mut nth: usize, x: u32) -> Option<usize> {
+        if nth == 0 || nth > count_x(&self.root, x) {
+            return None;
+        }
+
+        let mut pos = 0;
+        let mut t = &self.root;
+        while let Some(n) = t {
+            let in_left = count_x(&n.left, x);
+            if nth <= in_left {
+                t = &n.left;
+                continue;
+            }
+
+            nth -= in_left;
+            pos += size(&n.left);
+            let in_chunk = if x == 1 { n.ones } else { n.len - n.ones };
+            if nth <= in_chunk {
+                return Some(pos + n.chunk_select(nth, x));
+            }
+
+            nth -= in_chunk;
+            pos += n.len;
+            t = &n.right;
+        }
+        panic!("Should not be reached!");
+    }
+
+    // Convert back to a static bit vector, a whole BitCell at a time.
+    pub fn to_bit_vector(&self) -> BitVector {
+        fn append(t: &Tree, bits: &mut BitVector) {
+            if let Some(n) = t {
+                append(&n.left, bits);
+                bits.extend_from_words(&n.bits, n.len);
+                append(&n.right, bits);
+            }
+        }
+
+        let mut bits = BitVector::new_from_string("");
+        append(&self.root, &mut bits);
+        bits
+    }
+}
+
+impl RankSelectVector for DynamicBitVector {
+    fn new(bits: BitVector) -> Self {
+        // Leave room in every chunk, so that the first inserts do not split all chunks.
+        let chunk_bits = LEAF_BITS / 2;
+        let mut chunks = (0..bits.size()).step_by(chunk_bits).map(|start| {
+            Some(Node::from_bits(&bits, start, chunk_bits.min(bits.size() - start)))
+        }).collect::<Vec<_>>();
+
+        DynamicBitVector { root: build(&mut chunks) }
+    }
+
+    fn select1(&self, i: usize) -> Option<usize> {
+        self.generic_select(i, 1)
+    }
+
+    fn select0(&self, i: usize) -> Option<usize> {
+        self.generic_select(i, 0)
+    }
+
+    fn rank(&self, mut i: usize) -> usize {
+        assert!(i <= self.size());
+        let mut r = 0;
+        let mut t = &self.root;
+        while let Some(n) = t {
+            let ls = size(&n.left);
+            if i < ls {
+                t = &n.left;
+            } else if i <= ls + n.len {
+                return r + count1(&n.left) + n.chunk_rank(i - ls);
+            } else {
+                r += count1(&n.left) + n.ones;
+                i -= ls + n.len;
+                t = &n.right;
+            }
+        }
+        r
+    }
+
+    fn access(&self, mut i: usize) -> u32 {
+        assert!(i < self.size());
+        let mut t = &self.root;
+        while let Some(n) = t {
+            let ls = size(&n.left);
+            if i < ls {
+                t = &n.left;
+            } else if i < ls + n.len {
+                return n.get(i - ls);
+            } else {
+                i -= ls + n.len;
+                t = &n.right;
+            }
+        }
+        panic!("Should not be reached!");
+    }
+
+    fn get_memory_usage(&self) -> usize {
+        fn nodes(t: &Tree) -> usize {
+            t.as_ref().map_or(0, |n| 1 + nodes(&n.left) + nodes(&n.right))
+        }
+        nodes(&self.root) * std::mem::size_of::<Node>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tst::*;
+    use rand::{Rng, SeedableRng};
+    use rand_xoshiro::Xoshiro256Plus;
+
+    #[test]
+    fn sample() {
+        test_sample::<DynamicBitVector>();
+    }
+
+    #[test]
+    fn select() {
+        test_simple_select::<DynamicBitVector>();
+    }
+
+    // Check the AVL invariant and the subtree counts, returns the height.
+    fn check_tree(t: &Tree) -> u32 {
+        let Some(n) = t else {
+            return 0;
+        };
+
+        let (hl, hr) = (check_tree(&n.left), check_tree(&n.right));
+        assert!(hl.abs_diff(hr) <= 1 && n.height == 1 + hl.max(hr));
+        assert!(n.len > 0 && n.len <= LEAF_BITS && n.ones == n.chunk_rank(n.len));
+        assert_eq!(n.size, size(&n.left) + n.len + size(&n.right));
+        assert_eq!(n.count1, count1(&n.left) + n.ones + count1(&n.right));
+        n.height
+    }
+
+    fn check_against(dbv: &DynamicBitVector, model: &[u32], seed: u64) {
+        check_tree(&dbv.root);
+        assert_eq!(dbv.size(), model.len());
+        let bits = model.iter().map(|b| if *b == 1 { '1' } else { '0' }).collect::<String>();
+        assert_eq!(dbv.to_bit_vector().words(), BitVector::new_from_string(&bits).words());
+        compare_with_naive(dbv, &bits, 500, seed);
+    }
+
+    #[test]
+    fn random_updates() {
+        let mut rng = Xoshiro256Plus::seed_from_u64(9);
+        let initial = generate_random_bits_string(3000, 1, 0.5);
+        let mut dbv = DynamicBitVector::new(BitVector::new_from_string(&initial));
+        let mut model = initial.bytes().map(|b| (b - b'0') as u32).collect::<Vec<_>>();
+
+        for round in 0..40 {
+            for _ in 0..500 {
+                match rng.gen_range(0..4) {
+                    0 | 1 => {
+                        let i = rng.gen_range(0..=model.len());
+                        let bit = rng.gen_range(0..2);
+                        dbv.insert(i, bit);
+                        model.insert(i, bit);
+                    },
+                    2 if !model.is_empty() => {
+                        let i = rng.gen_range(0..model.len());
+                        assert_eq!(dbv.remove(i), model.remove(i));
+                    },
+                    _ if !model.is_empty() => {
+                        let i = rng.gen_range(0..model.len());
+                        model[i] = dbv.flip(i);
+                    },
+                    _ => {},
+                }
+            }
+            check_against(&dbv, &model, round);
+        }
+
+        // Remove everything again.
+        while !model.is_empty() {
+            let i = rng.gen_range(0..model.len());
+            assert_eq!(dbv.remove(i), model.remove(i));
+        }
+        assert_eq!(dbv.size(), 0);
+        assert_eq!(dbv.get_memory_usage(), 0);
+    }
+
+    #[test]
+    fn removals_merge_chunks() {
+        fn chunks(t: &Tree) -> usize {
+            t.as_ref().map_or(0, |n| 1 + chunks(&n.left) + chunks(&n.right))
+        }
+
+        let mut rng = Xoshiro256Plus::seed_from_u64(11);
+        let initial = generate_random_bits_string(20000, 2, 0.5);
+        let mut dbv = DynamicBitVector::new(BitVector::new_from_string(&initial));
+        let mut model = initial.bytes().map(|b| (b - b'0') as u32).collect::<Vec<_>>();
+
+        // Inserts split the chunks, then most bits are removed again at random positions. Without
+        // merging, this leaves about as many (tiny) chunks as after the inserts.
+        for round in 0..3 {
+            for _ in 0..20000 {
+                let (i, bit) = (rng.gen_range(0..=model.len()), rng.gen_range(0..2));
+                dbv.insert(i, bit);
+                model.insert(i, bit);
+            }
+            while model.len() > 1000 {
+                let i = rng.gen_range(0..model.len());
+                assert_eq!(dbv.remove(i), model.remove(i));
+            }
+            assert!(chunks(&dbv.root) <= model.len() / (LEAF_BITS / 2) + 2, "{} chunks for {} bits", chunks(&dbv.root), model.len());
+            check_against(&dbv, &model, round);
+        }
+    }
+
+    #[test]
+    fn append_only() {
+        let mut dbv = DynamicBitVector::new_empty();
+        let mut model = vec![];
+        for i in 0..5000 {
+            let bit = (i % 3 == 0) as u32;
+            dbv.push(bit);
+            model.push(bit);
+        }
+        check_against(&dbv, &model, 3);
+        assert_eq!(dbv.set(0, 0), 1);
+        assert_eq!(dbv.rank(1), 0);
+    }
+}
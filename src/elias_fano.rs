@@ -0,0 +1,238 @@
+use crate::bvec::{BitCell, BitVector, RankSelectVector, BIT_CELL_SIZE};
+use crate::fast_bvec::{DefaultParams, FastRASBVec, RASBVecParameters};
+use crate::int_vec::IntVector;
+
+// Elias-Fano encoding of a (sparse) bit vector.
+//
+// The positions of the m set bits in a vector of n bits are split into their lowest `l = log(n/m)`
+// bits, which are stored packed in `lower`, and the remaining high part. The high parts are stored
+// in unary in `upper`: the i-th set bit (0-based) with high part h is the bit h + i of `upper`.
+// This needs about m * (2 + log(n/m)) bits in total, instead of n bits for the plain BitVector.
+//
+// The rank/select queries on `upper` are answered with a FastRASBVec, so:
+// - select1 is one select1 on `upper` plus a lookup in `lower`,
+// - rank and access find the bucket of the high part with a select0 on `upper`, and then scan the
+//   (on average less than two) elements in that bucket,
+// - select0 binary searches the bucket which contains the i-th zero with select0 on `upper` (every
+//   bucket has 2^l positions, so the zeros before a bucket follow from the ones before it), and then
+//   skips the elements in that bucket.
+pub struct EliasFanoVec<Parameters: RASBVecParameters = DefaultParams> {
+    upper: FastRASBVec<Parameters>,
+    lower: IntVector,
+    size: usize,
+    count1: usize,
+}
+
+impl<Parameters: RASBVecParameters> EliasFanoVec<Parameters> {
+    // Build the encoding for a bit vector with `size` bits, where the set bits are exactly at
+    // `positions`, which must be strictly increasing.
+    pub fn from_positions(positions: &[usize], size: usize) -> Self {
+        Self::build(positions.iter().copied(), positions.len(), size)
+    }
+
+    fn build(positions: impl Iterator<Item = usize>, count1: usize, size: usize) -> Self {
+        let low_bits = size.checked_div(count1).map_or(0, |q| q.max(1).ilog2() as usize);
+
+        let upper_size = count1 + (size >> low_bits) + 1;
+        let mut upper: Vec<BitCell> = vec![0; upper_size.div_ceil(BIT_CELL_SIZE)];
+        let mut lower = IntVector::with_capacity(low_bits, count1);
+
+        let mut last = None;
+        for (i, p) in positions.enumerate() {
+            assert!(p < size && last.is_none_or(|last| last < p), "positions must be strictly increasing and smaller than the size");
+            last = Some(p);
+
+            let high = (p >> low_bits) + i;
+            upper[high / BIT_CELL_SIZE] |= (1 as BitCell) << (high % BIT_CELL_SIZE);
+            lower.push(p as u64);
+        }
+        assert_eq!(lower.len(), count1);
+
+        EliasFanoVec {
+            upper: FastRASBVec::new(BitVector::from_words(upper, upper_size)),
+            lower,
+            size,
+            count1,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn count_ones(&self) -> usize {
+        self.count1
+    }
+
+    // Number of elements with a high part smaller than `high`. These are exactly the ones before the
+    // `high`-th zero of `upper`, and the elements of bucket `high` start at bit `high` + this in `upper`.
+    fn ones_before_bucket(&self, high: usize) -> usize {
+        if high == 0 {
+            0
+        } else {
+            self.upper.select0(high).unwrap() + 1 - high
+        }
+    }
+
+    // Returns the number of set bits before position `p`, and whether `p` itself is set.
+    fn rank_and_access(&self, p: usize) -> (usize, bool) {
+        let low_bits = self.lower.width();
+        let high = p >> low_bits;
+        let low = p as u64 & ((1u64 << low_bits) - 1);
+
+        let mut idx = self.ones_before_bucket(high);
+        let mut pos = idx + high;
+
+        // Scan the elements with the same high part.
+        while pos < self.upper.size() && self.upper.access(pos) == 1 {
+            let x = self.lower.get(idx);
+            if x >= low {
+                return (idx, x == low);
+            }
+            idx += 1;
+            pos += 1;
+        }
+
+        (idx, false)
+    }
+}
+
+impl<Parameters: RASBVecParameters> RankSelectVector for EliasFanoVec<Parameters> {
+    fn new(bits: BitVector) -> Self {
+        let count1 = bits.count_ones(0, bits.size());
+        Self::build(bits.iter_ones(), count1, bits.size())
+    }
+
+    fn select1(&self, i: usize) -> Option<usize> {
+        if i == 0 || i > self.count1 {
+            return None;
+        }
+
+        let high = self.upper.select1(i).unwrap() - (i - 1);
+        Some((high << self.lower.width()) | self.lower.get(i - 1) as usize)
+    }
+
+    fn select0(&self, i: usize) -> Option<usize> {
+        if i == 0 || i > self.size - self.count1 {
+            return None;
+        }
+
+        let low_bits = self.lower.width();
+        let zeros_before_bucket = |high: usize| (high << low_bits) - self.ones_before_bucket(high);
+
+        // The bucket with the i-th zero is the last one with less than i zeros before it. Every bucket
+        // before position i - 1 qualifies, and there are at most count1 ones before the i-th zero.
+        let (mut l, mut r) = ((i - 1) >> low_bits, ((i - 1 + self.count1) >> low_bits).min((self.size - 1) >> low_bits));
+        while l < r {
+            let mid = (l + r).div_ceil(2);
+            if zeros_before_bucket(mid) < i {
+                l = mid;
+            } else {
+                r = mid - 1;
+            }
+        }
+
+        // The i-th zero is the j-th zero (0-based) in the bucket. Start at the j-th position of the
+        // bucket and skip the elements of the bucket which are at or before it.
+        let start = l << low_bits;
+        let mut idx = self.ones_before_bucket(l);
+        let mut pos = start + (i - 1 - (start - idx));
+        let mut upper_pos = idx + l;
+        while upper_pos < self.upper.size() && self.upper.access(upper_pos) == 1 {
+            if start + self.lower.get(idx) as usize > pos {
+                break;
+            }
+            pos += 1;
+            idx += 1;
+            upper_pos += 1;
+        }
+        Some(pos)
+    }
+
+    fn rank(&self, i: usize) -> usize {
+        self.rank_and_access(i).0
+    }
+
+    fn access(&self, i: usize) -> u32 {
+        assert!(i < self.size);
+        self.rank_and_access(i).1 as u32
+    }
+
+    // Unlike FastRASBVec, this is the memory for the whole representation, because the original
+    // bit vector is not kept.
+    fn get_memory_usage(&self) -> usize {
+        self.lower.get_memory_usage() + self.upper.bits().get_memory_usage() + self.upper.get_memory_usage()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fast_bvec::SmallRASB;
+    use crate::tst::*;
+
+    #[test]
+    fn sample() {
+        test_sample::<EliasFanoVec>();
+        test_sample::<EliasFanoVec<SmallRASB>>();
+    }
+
+    #[test]
+    fn select() {
+        test_simple_select::<EliasFanoVec>();
+    }
+
+    fn compare_ef_with_naive(bits: &str, nr_queries: usize) {
+        let ef = EliasFanoVec::<SmallRASB>::new(BitVector::new_from_string(bits));
+        compare_with_naive(&ef, bits, nr_queries, 7);
+    }
+
+    #[test]
+    fn random() {
+        compare_ef_with_naive(&generate_random_bits_string(3000, 1, 0.5), 3000);
+        compare_ef_with_naive(&generate_random_bits_string(20000, 2, 0.999), 5000);
+        compare_ef_with_naive(&generate_random_bits_string(1000, 3, 0.01), 2000);
+    }
+
+    #[test]
+    fn all_equal() {
+        for bits in ["0".repeat(100), "1".repeat(100), "0".to_string(), "1".to_string()] {
+            let ef = EliasFanoVec::<SmallRASB>::new(BitVector::new_from_string(&bits));
+            let slowb = BitVector::new_from_string(&bits);
+            for i in 0..=bits.len() {
+                assert_eq!(ef.rank(i), slowb.rank(i));
+                assert_eq!(ef.select0(i), slowb.select0(i));
+                assert_eq!(ef.select1(i), slowb.select1(i));
+                if i < bits.len() {
+                    assert_eq!(ef.access(i), slowb.access(i));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn select0_every_zero() {
+        // Buckets which are full, empty, and in between, for several widths of the low part.
+        for (n, weight0, seed) in [(1000, 0.5, 4), (5000, 0.99, 5), (2000, 0.1, 6), (777, 0.0001, 7)] {
+            let bits = BitVector::new_from_string(&generate_random_bits_string(n, seed, weight0));
+            let ef = EliasFanoVec::<SmallRASB>::new(bits.clone());
+            let zeros = n - bits.rank(n);
+            for i in 0..=zeros + 1 {
+                assert_eq!(ef.select0(i), bits.select0(i), "select0({}) n = {}", i, n);
+            }
+        }
+    }
+
+    #[test]
+    fn from_positions() {
+        let positions = [3, 10, 11, 500, 9999];
+        let ef = EliasFanoVec::<DefaultParams>::from_positions(&positions, 10000);
+        for (i, p) in positions.iter().enumerate() {
+            assert_eq!(ef.select1(i + 1), Some(*p));
+            assert_eq!(ef.rank(*p), i);
+            assert_eq!(ef.access(*p), 1);
+        }
+        assert_eq!(ef.rank(10000), positions.len());
+        assert!(ef.get_memory_usage() < BitVector::new_from_string(&"0".repeat(10000)).get_memory_usage());
+    }
+}
@@ -0,0 +1,112 @@
+// A sorted array of usize stored in Eytzinger (BFS) order: the root of the implicit binary search
+// tree is at index 1 and the children of node k are at 2k and 2k + 1.
+//
+// A binary search over a sorted array jumps through the whole array, so for arrays which do not fit
+// in the cache every probe is a cache miss. In Eytzinger order the first levels of the tree are
+// next to each other at the start of the array, and the 16 descendants of a node four levels down
+// are next to each other, so they are prefetched while the next levels are compared. See Khuong,
+// Morin, "Array Layouts for Comparison-Based Searching".
+//
+// The tree is padded with usize::MAX to a perfect binary tree of `height` levels, so that the index
+// of a node in the sorted order can be computed from its position, without another memory access.
+pub(crate) struct Eytzinger {
+    // values[0] is unused.
+    values: Vec<usize>,
+    len: usize,
+    height: u32,
+}
+
+impl Eytzinger {
+    pub(crate) fn from_sorted(sorted: &[usize]) -> Self {
+        debug_assert!(sorted.is_sorted());
+
+        let height = (sorted.len() + 1).next_power_of_two().trailing_zeros();
+        let mut eytzinger = Eytzinger {
+            values: vec![usize::MAX; 1 << height],
+            len: sorted.len(),
+            height,
+        };
+        eytzinger.fill(sorted, 1, &mut 0);
+        eytzinger
+    }
+
+    // In-order traversal of the tree, which visits the nodes in sorted order.
+    fn fill(&mut self, sorted: &[usize], k: usize, next: &mut usize) {
+        if k >= self.values.len() {
+            return;
+        }
+        self.fill(sorted, 2 * k, next);
+        if *next < sorted.len() {
+            self.values[k] = sorted[*next];
+        }
+        *next += 1;
+        self.fill(sorted, 2 * k + 1, next);
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    // Index (in the sorted array) of the first value which is >= x, or len() if there is none.
+    #[inline]
+    pub(crate) fn lower_bound(&self, x: usize) -> usize {
+        let mut k = 1;
+        while k < self.values.len() {
+            // The 16 descendants of k four levels down, in two cache lines.
+            prefetch(self.values.as_ptr().wrapping_add(16 * k));
+            prefetch(self.values.as_ptr().wrapping_add(16 * k + 8));
+            k = 2 * k + (self.values[k] < x) as usize;
+        }
+
+        // k went right after the answer, and then only left: remove those steps.
+        k >>= k.trailing_ones() + 1;
+        if k == 0 {
+            return self.len;
+        }
+
+        // In a perfect tree, the node at position p of level d is the (2p + 1)'th of the 2^(d + 1)
+        // equal parts of the sorted order.
+        let depth = k.ilog2();
+        let index = ((2 * (k - (1 << depth)) + 1) << (self.height - 1 - depth)) - 1;
+        index.min(self.len)
+    }
+
+    pub(crate) fn get_memory_usage(&self) -> usize {
+        self.values.len() * std::mem::size_of::<usize>()
+    }
+}
+
+// Hint the CPU to load the cache line which contains `p`. `p` does not need to be valid, prefetches
+// never fault.
+#[inline(always)]
+pub(crate) fn prefetch<T>(p: *const T) {
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        use core::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+        _mm_prefetch::<_MM_HINT_T0>(p as *const i8);
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = p;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand_xoshiro::Xoshiro256Plus;
+
+    #[test]
+    fn lower_bound() {
+        let mut rng = Xoshiro256Plus::seed_from_u64(17);
+        for n in (0..40).chain([255, 256, 1000]) {
+            let mut sorted = (0..n).map(|_| rng.gen_range(0..3 * n + 1)).collect::<Vec<_>>();
+            sorted.sort();
+            let eytzinger = Eytzinger::from_sorted(&sorted);
+            assert_eq!(eytzinger.len(), n);
+
+            for x in 0..=3 * n + 2 {
+                assert_eq!(eytzinger.lower_bound(x), sorted.partition_point(|v| *v < x), "n={} x={}", n, x);
+            }
+        }
+    }
+}
@@ -1,11 +1,16 @@
 use num::Integer;
 
 use crate::bvec::*;
+use crate::config::{ConfigError, RankSelectConfig};
+use crate::eytzinger::{prefetch, Eytzinger};
+use crate::query::Query;
+use crate::storage::Storage;
 use derivative::Derivative;
+use std::marker::PhantomData;
 
 type Superblock = usize;
-type Block = u32;
-type CacheBlock = u8;
+pub(crate) type Block = u32;
+pub(crate) type CacheBlock = u8;
 const CACHE_BLOCK_BITS: usize = std::mem::size_of::<CacheBlock>() * 8;
 
 // Parameters for the data structure (size of block size, size of superblock size, size of megablock factor)
@@ -14,6 +19,12 @@ pub trait RASBVecParameters {
     const BLOCK_SIZE: usize;
     const SUPERBLOCK_SIZE: usize;
     const MEGABLOCK_FACTOR: usize = 32; // how many superblocks are contained in a megablock
+    // Record the superblock of every SELECT_SAMPLE_RATE-th one and zero, so that select does not need
+    // to binary search the megablocks. 0 disables the samples.
+    const SELECT_SAMPLE_RATE: usize = 0;
+    // Also store the megablocks in Eytzinger (BFS) order, which is searched instead of the sorted
+    // megablocks. Ignored if select samples are used.
+    const EYTZINGER_MEGABLOCKS: bool = false;
 
     // Computations about how much bits we need to store in the RankSuperblock data structure.
     const SUPERBLOCK_BITS: usize = std::mem::size_of::<usize>() * 8;
@@ -21,128 +32,307 @@ pub trait RASBVecParameters {
     const CACHELINE_SIZE: usize = (Self::SUPERBLOCK_BITS + (Self::SUPERBLOCK_SIZE / Self::BLOCK_SIZE) * Self::BLOCK_BITS).div_ceil(8);
 }
 
+// Generic parameter set, so that layouts can be chosen inline:
+// Params<BLOCK_SIZE, SUPERBLOCK_SIZE, MEGABLOCK_FACTOR, SUPERBLOCK_BITS, SELECT_SAMPLE_RATE, EYTZINGER_MEGABLOCKS>.
+pub struct Params<const A: usize, const B: usize, const C: usize = 1, const SUPERBITS: usize = 40, const SAMPLES: usize = 0,
+    const EYTZINGER: bool = false>;
+
+impl<const A: usize, const B: usize, const C: usize, const D: usize, const E: usize, const F: bool> RASBVecParameters for Params<A, B, C, D, E, F> {
+    const BLOCK_SIZE: usize = A;
+    const SUPERBLOCK_SIZE: usize = B;
+    const MEGABLOCK_FACTOR: usize = C;
+    const SUPERBLOCK_BITS: usize = D;
+    const SELECT_SAMPLE_RATE: usize = E;
+    const EYTZINGER_MEGABLOCKS: bool = F;
+}
+
+// The layout used by the main program, it has the best trade-off between memory and query speed
+// in our benchmarks.
+pub type DefaultParams = Params<4096, 32768, 32, 48>;
+
+// The layout of the rank/select directory as seen by the implementation. It is implemented by
+// ConstLayout for compile-time RASBVecParameters (where all values are constants, so that the
+// compiler can optimize the divisions away), and by RankSelectConfig for layouts chosen at runtime.
+pub trait RASBVecLayout: Clone + Default {
+    fn block_size(&self) -> usize;
+    fn superblock_size(&self) -> usize;
+    fn megablock_factor(&self) -> usize;
+    fn superblock_bits(&self) -> usize;
+    fn block_bits(&self) -> usize;
+    fn cacheline_size(&self) -> usize;
+    fn select_sample_rate(&self) -> usize;
+    fn eytzinger_megablocks(&self) -> bool;
+
+    // The layout with the given values, if this layout type can represent them. This is used when
+    // loading a serialized structure, see persist.rs. The select sample rate and the Eytzinger flag
+    // only decide what is computed after loading, so a compile-time layout keeps its own.
+    fn from_values(block_size: usize, superblock_size: usize, megablock_factor: usize, superblock_bits: usize,
+                   select_sample_rate: usize, eytzinger_megablocks: bool) -> Option<Self>;
+
+    fn blocks_per_superblock(&self) -> usize {
+        self.superblock_size() / self.block_size()
+    }
+}
+
 #[derive(Derivative)]
-#[derivative(Clone(bound=""), Debug)]
-struct RankSuperblock<Parameters: RASBVecParameters> where [CacheBlock; Parameters::CACHELINE_SIZE]: Sized {
+#[derivative(Clone(bound=""), Copy(bound=""), Default(bound=""), Debug(bound=""))]
+pub struct ConstLayout<Parameters: RASBVecParameters> {
+    pd: PhantomData<Parameters>,
+}
+
+impl<Parameters: RASBVecParameters> RASBVecLayout for ConstLayout<Parameters> {
+    #[inline(always)]
+    fn block_size(&self) -> usize {
+        Parameters::BLOCK_SIZE
+    }
+
+    #[inline(always)]
+    fn superblock_size(&self) -> usize {
+        Parameters::SUPERBLOCK_SIZE
+    }
+
+    #[inline(always)]
+    fn megablock_factor(&self) -> usize {
+        Parameters::MEGABLOCK_FACTOR
+    }
+
+    #[inline(always)]
+    fn superblock_bits(&self) -> usize {
+        Parameters::SUPERBLOCK_BITS
+    }
+
+    #[inline(always)]
+    fn block_bits(&self) -> usize {
+        Parameters::BLOCK_BITS
+    }
+
+    #[inline(always)]
+    fn cacheline_size(&self) -> usize {
+        Parameters::CACHELINE_SIZE
+    }
+
+    #[inline(always)]
+    fn select_sample_rate(&self) -> usize {
+        Parameters::SELECT_SAMPLE_RATE
+    }
+
+    #[inline(always)]
+    fn eytzinger_megablocks(&self) -> bool {
+        Parameters::EYTZINGER_MEGABLOCKS
+    }
+
+    fn from_values(block_size: usize, superblock_size: usize, megablock_factor: usize, superblock_bits: usize,
+                   _select_sample_rate: usize, _eytzinger_megablocks: bool) -> Option<Self> {
+        let matches = block_size == Parameters::BLOCK_SIZE && superblock_size == Parameters::SUPERBLOCK_SIZE &&
+            megablock_factor == Parameters::MEGABLOCK_FACTOR && superblock_bits == Parameters::SUPERBLOCK_BITS;
+        matches.then(Self::default)
+    }
+}
+
+struct RankSuperblock<'a, Layout: RASBVecLayout, Data> {
     // Idea of RankSuperblock: store the value for a superblock, then immediately after it store
     // the data for all the blocks inside the superblock. Each value has its own number of bits,
     // which does not necessarily correspond to basic types like u32.
     //
-    // The data is stored packed in a simple [CacheBlock] == [u8] array, and the corresponding bits are
-    // extracted with bit operations and loops every time they are needed.
+    // The data is stored packed in a simple [CacheBlock] == [u8] array of `layout.cacheline_size()`
+    // elements, as a little-endian bit stream: bit b of the stream is bit b % 8 of byte b / 8.
     // Benchmarks show that this significantly lowers memory usage, but does not increase runtimes
     // by much.
-    data: [CacheBlock; Parameters::CACHELINE_SIZE],
+    //
+    // A value is read with a single unaligned 64-bit load starting at the byte which contains its
+    // first bit, followed by a shift and a mask. To make this possible, the view is RANK_PADDING bytes
+    // longer than the superblock: these bytes belong to the next superblock (or to the padding at the
+    // end of the RankSupport), and are never interpreted.
+    //
+    // The RankSuperblock itself is only a view (`&[CacheBlock]` or `&mut [CacheBlock]`) into the
+    // RankSupport storage, so that the size of the array does not need to be known by the type
+    // system.
+    data: Data,
+    layout: &'a Layout,
+}
+
+// Number of bytes which must be readable after the end of a RankSuperblock.
+pub(crate) const RANK_PADDING: usize = 8;
+
+fn mask_n_bits(x: usize) -> u64 {
+    if x >= 64 { u64::MAX } else { (1 << x) - 1 }
 }
 
-impl<Parameters: RASBVecParameters> RankSuperblock<Parameters> where [CacheBlock; Parameters::CACHELINE_SIZE]: Sized {
-    fn new() -> Self {
-        RankSuperblock::<Parameters> {
-            data: [0; Parameters::CACHELINE_SIZE],
+impl<'a, Layout: RASBVecLayout, Data: AsRef<[CacheBlock]>> RankSuperblock<'a, Layout, Data> {
+    fn new(data: Data, layout: &'a Layout) -> Self {
+        debug_assert_eq!(data.as_ref().len(), layout.cacheline_size() + RANK_PADDING);
+        RankSuperblock {
+            data,
+            layout,
         }
     }
 
-    fn mask_n_bits(x: usize) -> CacheBlock {
-        if x == 0 {
-            return 0;
-        }
-        CacheBlock::MAX >> (CACHE_BLOCK_BITS - x)
+    #[inline(always)]
+    fn load_word(data: &[CacheBlock], byte: usize) -> u64 {
+        u64::from_le_bytes(data[byte..byte + 8].try_into().unwrap())
     }
 
+    #[inline(always)]
     fn extract_bits(&self, l: usize, r: usize) -> u64 {
-        let (lb, shiftl) = l.div_rem(&CACHE_BLOCK_BITS);
-        let (rb, shiftr) = r.div_rem(&CACHE_BLOCK_BITS);
+        let data = self.data.as_ref();
+        let (lb, shift) = l.div_rem(&CACHE_BLOCK_BITS);
 
-        if lb == rb {
-            return ((self.data[lb] & Self::mask_n_bits(shiftr)) >> shiftl) as u64;
-        }
-
-        let mut result: u64 = (self.data[lb] >> shiftl) as u64;
-        let mut shift: usize = CACHE_BLOCK_BITS - shiftl;
+        // Only the superblock value can have more than 57 bits, and it starts at a byte boundary.
+        debug_assert!(shift + (r - l) <= 64);
+        (Self::load_word(data, lb) >> shift) & mask_n_bits(r - l)
+    }
 
-        for i in (lb + 1)..rb {
-            result |= (self.data[i] as u64) << shift;
-            shift += CACHE_BLOCK_BITS;
-        }
+    fn superblock(&self) -> Superblock {
+        self.extract_bits(0, self.layout.superblock_bits()) as usize
+    }
 
-        if shiftr > 0 {
-            result |= ((self.data[rb] & Self::mask_n_bits(shiftr)) as u64) << shift;
-        }
+    fn block(&self, i: usize) -> Block {
+        let start = self.layout.superblock_bits() + i * self.layout.block_bits();
+        self.extract_bits(start, start + self.layout.block_bits()) as Block
+    }
+}
 
-        result
+impl<Layout: RASBVecLayout, Data: AsRef<[CacheBlock]> + AsMut<[CacheBlock]>> RankSuperblock<'_, Layout, Data> {
+    // Replace the bits [l, r) with `value`. The other bits of the loaded word are written back
+    // unchanged.
+    fn write_bits(&mut self, l: usize, r: usize, value: u64) {
+        let data = self.data.as_mut();
+        let (lb, shift) = l.div_rem(&CACHE_BLOCK_BITS);
+        debug_assert!(shift + (r - l) <= 64);
+        let mask = mask_n_bits(r - l);
+
+        let word = (Self::load_word(data, lb) & !(mask << shift)) | ((value & mask) << shift);
+        data[lb..lb + 8].copy_from_slice(&word.to_le_bytes());
     }
 
-    fn write_bits(&mut self, l: usize, r: usize, mut value: u64) {
-        let (lb, shiftl) = l.div_rem(&CACHE_BLOCK_BITS);
-        let (rb, shiftr) = r.div_rem(&CACHE_BLOCK_BITS);
+    fn set_super(&mut self, value: Superblock) {
+        self.write_bits(0, self.layout.superblock_bits(), value as u64);
+    }
 
-        self.data[lb] |= (value << shiftl) as CacheBlock;
-        if lb == rb {
-            return;
-        }
+    fn set_block(&mut self, i: usize, value: Block) {
+        let start = self.layout.superblock_bits() + i * self.layout.block_bits();
+        self.write_bits(start, start + self.layout.block_bits(), value as u64);
+    }
+}
 
-        value >>= CACHE_BLOCK_BITS - shiftl;
-        for i in lb + 1..rb {
-            self.data[i] = (value & Self::mask_n_bits(CACHE_BLOCK_BITS) as u64) as CacheBlock;
-            value >>= CACHE_BLOCK_BITS;
-        }
+// Storage for all RankSuperblocks. They are packed back to back in a single array, each of them
+// taking exactly `layout.cacheline_size()` bytes. This is the same memory layout as
+// Vec<[CacheBlock; CACHELINE_SIZE]>, without needing generic_const_exprs for it.
+//
+// The array is followed by RANK_PADDING bytes, so that the 64-bit loads of the last RankSuperblock
+// stay inside it. Their content does not matter: in a memory-mapped file they are the bytes which
+// follow the RankSuperblock data (see persist.rs).
+#[derive(Clone, Debug)]
+pub(crate) struct RankSupport<Layout: RASBVecLayout> {
+    pub(crate) data: Storage<CacheBlock>,
+    pub(crate) layout: Layout,
+}
 
-        if shiftr > 0 {
-            self.data[rb] |= value as CacheBlock;
+impl<Layout: RASBVecLayout> RankSupport<Layout> {
+    fn with_superblocks(n: usize, layout: Layout) -> Self {
+        RankSupport {
+            data: vec![0; n * layout.cacheline_size() + RANK_PADDING].into(),
+            layout,
         }
     }
 
-    fn superblock(&self) -> Superblock {
-        self.extract_bits(0, Parameters::SUPERBLOCK_BITS) as usize
+    fn len(&self) -> usize {
+        self.packed().len() / self.layout.cacheline_size()
     }
 
-    fn set_super(&mut self, value: Superblock) {
-        self.write_bits(0, Parameters::SUPERBLOCK_BITS, value as u64);
+    // The RankSuperblocks without the padding.
+    pub(crate) fn packed(&self) -> &[CacheBlock] {
+        &self.data[..self.data.len() - RANK_PADDING]
     }
 
-    fn block(&self, i: usize) -> Block {
-        let start = Parameters::SUPERBLOCK_BITS + i * Parameters::BLOCK_BITS;
-        self.extract_bits(start, start + Parameters::BLOCK_BITS) as Block
+    // Append a RankSuperblock with all values set to zero.
+    fn push_superblock(&mut self) {
+        let cacheline_size = self.layout.cacheline_size();
+        self.data.with_vec(|v| {
+            v.truncate(v.len() - RANK_PADDING);
+            v.resize(v.len() + cacheline_size + RANK_PADDING, 0);
+        });
     }
 
-    fn set_block(&mut self, i: usize, value: Block) {
-        let start = Parameters::SUPERBLOCK_BITS + i * Parameters::BLOCK_BITS;
-        self.write_bits(start, start + Parameters::BLOCK_BITS, value as u64);
+    fn superblock(&self, i: usize) -> RankSuperblock<'_, Layout, &[CacheBlock]> {
+        let start = i * self.layout.cacheline_size();
+        RankSuperblock::new(&self.data[start..start + self.layout.cacheline_size() + RANK_PADDING], &self.layout)
     }
-}
 
-struct RankSupport<Parameters: RASBVecParameters> where [CacheBlock; Parameters::CACHELINE_SIZE]: Sized {
-    superblocks: Vec<RankSuperblock<Parameters>>,
+    fn superblock_mut(&mut self, i: usize) -> RankSuperblock<'_, Layout, &mut [CacheBlock]> {
+        let start = i * self.layout.cacheline_size();
+        RankSuperblock::new(&mut self.data.make_mut()[start..start + self.layout.cacheline_size() + RANK_PADDING], &self.layout)
+    }
 }
 
 // The main structure. It has superblocks + blocks together in the rank support.
 // megablocks are kept separately for faster (due to cache efficiency) binary search in select
 // queries.
-pub struct FastRASBVec<Parameters: RASBVecParameters> where [CacheBlock; Parameters::CACHELINE_SIZE]: Sized {
-    bits: BitVector,
-    rank: RankSupport<Parameters>,
-    megablocks: Vec<usize>,
-    count0: usize,
-    count1: usize,
-    pd: std::marker::PhantomData<Parameters>,
+//
+// If the layout has a select sample rate k, select_samples[x] contains the superblock of every k-th
+// bit with value x (the bits number 1, k + 1, 2k + 1, ...). select then only searches the superblocks
+// between two samples instead of the megablocks.
+//
+// If the layout uses Eytzinger megablocks, eytzinger_megablocks[x] contains the number of bits with
+// value x before every megablock, in Eytzinger order (see eytzinger.rs). select searches them instead
+// of the sorted megablocks, which are still kept for push() and for the file format. push() only
+// rebuilds them when the number of megablocks reaches a power of two, so they may miss the last ones.
+//
+// The structure is generic over the layout, usually it is used through one of the two aliases:
+// FastRASBVec (layout fixed at compile time) or RuntimeRASBVec (layout from a RankSelectConfig).
+pub struct RASBVec<Layout: RASBVecLayout> {
+    pub(crate) bits: BitVector,
+    pub(crate) rank: RankSupport<Layout>,
+    pub(crate) megablocks: Vec<usize>,
+    pub(crate) select_samples: [Vec<usize>; 2],
+    pub(crate) eytzinger_megablocks: Option<[Eytzinger; 2]>,
+    pub(crate) count0: usize,
+    pub(crate) count1: usize,
+}
+
+// The specialised variant where the layout is known at compile time.
+pub type FastRASBVec<Parameters> = RASBVec<ConstLayout<Parameters>>;
+
+// The variant where the layout is chosen at runtime, see RankSelectConfig.
+pub type RuntimeRASBVec = RASBVec<RankSelectConfig>;
+
+impl RuntimeRASBVec {
+    // Build the rank/select directory for `bits` with the given layout.
+    pub fn with_config(bits: BitVector, config: RankSelectConfig) -> Result<Self, ConfigError> {
+        config.check_size(bits.size())?;
+        let mut vec = Self::new_empty_with_layout(config);
+        vec.initialize_for(bits);
+        Ok(vec)
+    }
+
+    // Like `with_config`, with the rank directory built by `threads` threads.
+    pub fn with_config_parallel(bits: BitVector, config: RankSelectConfig, threads: usize) -> Result<Self, ConfigError> {
+        config.check_size(bits.size())?;
+        let mut vec = Self::new_empty_with_layout(config);
+        vec.initialize_for_parallel(bits, threads);
+        Ok(vec)
+    }
 }
 
 #[allow(dead_code)]
-impl<Parameters: RASBVecParameters> FastRASBVec<Parameters> where [CacheBlock; Parameters::CACHELINE_SIZE]: Sized {
+impl<Layout: RASBVecLayout> RASBVec<Layout> {
     pub fn size(&self) -> usize {
         self.bits.size()
     }
 
     pub fn new_empty() -> Self {
-        FastRASBVec::<Parameters> {
-            bits: BitVector::new_from_string("0"),
-            rank: RankSupport {
-                superblocks: vec![],
-            },
+        Self::new_empty_with_layout(Layout::default())
+    }
+
+    fn new_empty_with_layout(layout: Layout) -> Self {
+        RASBVec {
+            bits: BitVector::new_from_string(""),
+            rank: RankSupport::with_superblocks(0, layout),
             megablocks: vec![],
+            select_samples: [vec![], vec![]],
+            eytzinger_megablocks: None,
             count0: 0,
             count1: 0,
-            pd: std::marker::PhantomData,
         }
     }
 
@@ -151,64 +341,296 @@ impl<Parameters: RASBVecParameters> FastRASBVec<Parameters> where [CacheBlock; P
         self.bits = bits;
     }
 
-    pub fn blocks_per_superblock() -> usize {
-        Parameters::SUPERBLOCK_SIZE / Parameters::BLOCK_SIZE
+    // Like `new`, but the rank directory is built with `threads` threads. The result is the same as
+    // with `new`.
+    pub fn new_parallel(bits: BitVector, threads: usize) -> Self where Layout: Send {
+        let mut vec = Self::new_empty();
+        vec.initialize_for_parallel(bits, threads);
+        vec
+    }
+
+    pub fn initialize_for_parallel(&mut self, bits: BitVector, threads: usize) where Layout: Send {
+        self.init_rank_parallel(&bits, threads);
+        self.bits = bits;
+    }
+
+    // Append a bit at the end, keeping the rank/select directory up to date. Amortized O(1).
+    //
+    // Only the block which contains position size() is kept up to date in the last superblock, the
+    // values of the blocks after it are set when the first bit after them is pushed.
+    pub fn push(&mut self, bit: u32) {
+        assert!(bit <= 1);
+        let layout = self.layout().clone();
+        let i = self.size();
+
+        if i.is_multiple_of(layout.superblock_size()) {
+            let sb = i / layout.superblock_size();
+            assert!((self.count1 as u64).checked_shr(layout.superblock_bits() as u32).unwrap_or(0) == 0,
+                "Too many set bits for the superblock bits of the layout.");
+
+            if sb.is_multiple_of(layout.megablock_factor()) {
+                self.megablocks.push(self.count1);
+                // Rebuilding the Eytzinger megablocks costs O(megablocks). Doing it only when their
+                // number doubles keeps the total cost O(megablocks) over all pushes, so O(1) per bit.
+                // In between, they cover a prefix of the megablocks, see find_superblock.
+                if self.megablocks.len().is_power_of_two() {
+                    self.init_eytzinger_megablocks();
+                }
+            }
+            self.rank.push_superblock();
+            self.rank.superblock_mut(sb).set_super(self.count1);
+        }
+
+        self.bits.push(bit);
+        self.count1 += bit as usize;
+        self.count0 += 1 - bit as usize;
+
+        let rate = layout.select_sample_rate();
+        let count = if bit == 1 { self.count1 } else { self.count0 };
+        if rate > 0 && (count - 1).is_multiple_of(rate) {
+            self.select_samples[bit as usize].push(i / layout.superblock_size());
+        }
+
+        // Start the next block, unless it is the first one of a new superblock.
+        let size = i + 1;
+        let (sb, in_sb) = size.div_rem(&layout.superblock_size());
+        if in_sb != 0 && in_sb.is_multiple_of(layout.block_size()) {
+            let mut sblock = self.rank.superblock_mut(sb);
+            let count = self.count1 - sblock.superblock();
+            sblock.set_block(in_sb / layout.block_size(), count as Block);
+        }
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.rank.layout
+    }
+
+    // The underlying bits, which are not counted in get_memory_usage.
+    pub fn bits(&self) -> &BitVector {
+        &self.bits
+    }
+
+    pub fn blocks_per_superblock(&self) -> usize {
+        self.layout().blocks_per_superblock()
     }
 
     // Compute the data needed for blocks, superblocks and megablocks.
     fn init_rank(&mut self, bits: &BitVector) {
-        let n_super = bits.size().div_ceil(Parameters::SUPERBLOCK_SIZE);
+        let layout = self.layout().clone();
+        let n_super = bits.size().div_ceil(layout.superblock_size());
 
-        let mut rk = RankSupport {
-            superblocks: vec![RankSuperblock::new(); n_super],
-        };
+        let mut rk = RankSupport::with_superblocks(n_super, layout.clone());
 
-        let mut megablocks = vec![];
-        megablocks.reserve(n_super.div_floor(Parameters::MEGABLOCK_FACTOR));
+        let mut megablocks = Vec::with_capacity(n_super.div_ceil(layout.megablock_factor()));
 
         let mut total_count: Superblock = 0;
         for i in 0..n_super {
-            let mut sblock_count: Block = 0;
-            assert!(Block::MAX as usize >= (Self::blocks_per_superblock() - 1) * Parameters::BLOCK_SIZE,
-                "Superblock size is too big for block max type.");
-
-            if i % Parameters::MEGABLOCK_FACTOR == 0 {
+            if i % layout.megablock_factor() == 0 {
                 megablocks.push(total_count);
             }
 
-            rk.superblocks[i].set_super(total_count);
-            for j in 0..Self::blocks_per_superblock() {
-                rk.superblocks[i].set_block(j, sblock_count);
+            rk.superblock_mut(i).set_super(total_count);
+            total_count += Self::init_blocks(&mut rk, i, bits, i);
+        }
 
-                let block_start = i * Parameters::SUPERBLOCK_SIZE + j * Parameters::BLOCK_SIZE;
-                if block_start < bits.size() {
-                    let block_end = std::cmp::min(block_start + Parameters::BLOCK_SIZE, bits.size());
-                    sblock_count += bits.count_ones(block_start, block_end) as Block;
-                }
+        self.count1 = total_count;
+        self.count0 = bits.size() - total_count;
+        self.rank = rk;
+        self.megablocks = megablocks;
+        self.init_select_samples();
+        self.init_eytzinger_megablocks();
+    }
+
+    // Set the blocks of superblock `i` of `rk`, which is superblock `sb` of `bits`. Returns the number
+    // of set bits in the superblock.
+    fn init_blocks(rk: &mut RankSupport<Layout>, i: usize, bits: &BitVector, sb: usize) -> usize {
+        let layout = rk.layout.clone();
+        assert!(Block::MAX as usize >= (layout.blocks_per_superblock() - 1) * layout.block_size(),
+            "Superblock size is too big for block max type.");
+
+        let mut sblock_count: Block = 0;
+        let mut sblock = rk.superblock_mut(i);
+        for j in 0..layout.blocks_per_superblock() {
+            sblock.set_block(j, sblock_count);
+
+            let block_start = sb * layout.superblock_size() + j * layout.block_size();
+            if block_start < bits.size() {
+                let block_end = std::cmp::min(block_start + layout.block_size(), bits.size());
+                sblock_count += bits.count_ones(block_start, block_end) as Block;
+            }
+        }
+        sblock_count as usize
+    }
+
+    // Same result as init_rank, with `threads` threads. The superblocks are split into one chunk per
+    // thread, and every chunk is built in its own RankSupport, so that the threads never write to the
+    // same memory (the writes of a RankSuperblock may touch the first bytes of the next one):
+    //
+    // 1. every thread sets the blocks of its superblocks and counts the set bits in them,
+    // 2. the prefix sum over the chunk totals gives the number of set bits before every chunk,
+    // 3. every thread sets its superblock values and collects its megablocks,
+    // 4. the chunks are concatenated.
+    fn init_rank_parallel(&mut self, bits: &BitVector, threads: usize) where Layout: Send {
+        let layout = self.layout().clone();
+        let n_super = bits.size().div_ceil(layout.superblock_size());
+        let chunk_size = n_super.div_ceil(threads.max(1)).max(1);
+
+        struct Chunk<Layout: RASBVecLayout> {
+            start: usize,
+            rank: RankSupport<Layout>,
+            // Number of set bits in every superblock of the chunk.
+            counts: Vec<usize>,
+            megablocks: Vec<usize>,
+        }
+
+        let mut chunks = std::thread::scope(|s| {
+            let handles = (0..n_super).step_by(chunk_size).map(|start| {
+                let layout = layout.clone();
+                s.spawn(move || {
+                    let len = chunk_size.min(n_super - start);
+                    let mut rank = RankSupport::with_superblocks(len, layout);
+                    let counts = (0..len).map(|i| Self::init_blocks(&mut rank, i, bits, start + i)).collect();
+                    Chunk { start, rank, counts, megablocks: vec![] }
+                })
+            }).collect::<Vec<_>>();
+            handles.into_iter().map(|h| h.join().unwrap()).collect::<Vec<_>>()
+        });
+
+        let mut offsets = Vec::with_capacity(chunks.len());
+        let mut total_count: Superblock = 0;
+        for chunk in &chunks {
+            offsets.push(total_count);
+            total_count += chunk.counts.iter().sum::<usize>();
+        }
+
+        std::thread::scope(|s| {
+            for (chunk, offset) in chunks.iter_mut().zip(offsets) {
+                let megablock_factor = layout.megablock_factor();
+                s.spawn(move || {
+                    let mut count = offset;
+                    for i in 0..chunk.counts.len() {
+                        if (chunk.start + i).is_multiple_of(megablock_factor) {
+                            chunk.megablocks.push(count);
+                        }
+                        chunk.rank.superblock_mut(i).set_super(count);
+                        count += chunk.counts[i];
+                    }
+                });
             }
+        });
 
-            total_count += sblock_count as usize;
+        let mut data = Vec::with_capacity(n_super * layout.cacheline_size() + RANK_PADDING);
+        let mut megablocks = Vec::with_capacity(n_super.div_ceil(layout.megablock_factor()));
+        for chunk in &chunks {
+            data.extend_from_slice(chunk.rank.packed());
+            megablocks.extend_from_slice(&chunk.megablocks);
         }
+        data.resize(data.len() + RANK_PADDING, 0);
 
         self.count1 = total_count;
         self.count0 = bits.size() - total_count;
-        self.rank = rk;
+        self.rank = RankSupport { data: data.into(), layout };
         self.megablocks = megablocks;
+        self.init_select_samples();
+        self.init_eytzinger_megablocks();
+    }
+
+    // Check that the counts of a loaded structure agree with each other: the set bits before the last
+    // superblock plus the ones after it must add up to count1, and every megablock must be the value
+    // of its first superblock, non-decreasing and at most the number of bits before it.
+    pub(crate) fn check_counts(&self) -> Result<(), String> {
+        let layout = self.layout();
+        let count1 = match self.rank.len() {
+            0 => 0,
+            n => self.value_count_before_sblock(n - 1, 1) + self.bits.count_ones((n - 1) * layout.superblock_size(), self.size()),
+        };
+        if count1 != self.count1 {
+            return Err(format!("{} set bits in the header, but {} in the superblocks", self.count1, count1));
+        }
+
+        let megablock_bits = layout.megablock_factor() * layout.superblock_size();
+        for (k, &megablock) in self.megablocks.iter().enumerate() {
+            if k > 0 && megablock < self.megablocks[k - 1] {
+                return Err(format!("megablock {} is smaller than the one before it", k));
+            }
+            if megablock > k * megablock_bits {
+                return Err(format!("megablock {} has {} set bits before it, more than its position", k, megablock));
+            }
+            let superblock = self.value_count_before_sblock(k * layout.megablock_factor(), 1);
+            if megablock != superblock {
+                return Err(format!("megablock {} is {}, but its first superblock is {}", k, megablock, superblock));
+            }
+        }
+        Ok(())
+    }
+
+    // Compute the Eytzinger megablocks from the sorted megablocks, if the layout uses them.
+    pub(crate) fn init_eytzinger_megablocks(&mut self) {
+        let layout = self.layout();
+        if !layout.eytzinger_megablocks() {
+            self.eytzinger_megablocks = None;
+            return;
+        }
+
+        let megablock_bits = layout.megablock_factor() * layout.superblock_size();
+        let zeros = self.megablocks.iter().enumerate().map(|(i, ones)| i * megablock_bits - ones).collect::<Vec<_>>();
+        self.eytzinger_megablocks = Some([Eytzinger::from_sorted(&zeros), Eytzinger::from_sorted(&self.megablocks)]);
+    }
+
+    // Compute the select samples from the superblocks, if the layout has a select sample rate.
+    pub(crate) fn init_select_samples(&mut self) {
+        let rate = self.layout().select_sample_rate();
+        self.select_samples = [vec![], vec![]];
+        if rate == 0 {
+            return;
+        }
+
+        for value in 0..2 {
+            let total = if value == 0 { self.count0 } else { self.count1 };
+            let mut samples = Vec::with_capacity(total.div_ceil(rate));
+            let mut sb = 0;
+            for target in (1..=total).step_by(rate) {
+                while sb + 1 < self.rank.len() && self.value_count_before_sblock(sb + 1, value) < target {
+                    sb += 1;
+                }
+                samples.push(sb);
+            }
+            self.select_samples[value as usize] = samples;
+        }
     }
 
     // Compute a simple rank query using the superblocks and blocks.
     fn _rank1(&self, i: usize) -> usize {
-        let (super_idx, super_rem) = i.div_rem(&Parameters::SUPERBLOCK_SIZE);
-        let (block_idx, block_rem) = super_rem.div_rem(&Parameters::BLOCK_SIZE);
+        let (super_idx, super_rem) = i.div_rem(&self.layout().superblock_size());
+        let (block_idx, block_rem) = super_rem.div_rem(&self.layout().block_size());
 
         //println!("super_idx: {}, super_rem: {}, block_idx: {}, block_rem: {}", super_idx, super_rem, block_idx, block_rem);
 
-        let mut r = self.rank.superblocks[super_idx].superblock();
-        r += self.rank.superblocks[super_idx].block(block_idx) as usize;
+        // rank(size) when the size is a multiple of the superblock size: there is no superblock
+        // starting at i.
+        if super_idx == self.rank.len() {
+            return self.count1;
+        }
+
+        let sblock = self.rank.superblock(super_idx);
+        let mut r = sblock.superblock();
+        r += sblock.block(block_idx) as usize;
         r += self.bits.count_ones(i - block_rem, i);
         r
     }
 
+    #[inline(always)]
+    fn prefetch_rank(&self, i: usize) {
+        let layout = self.layout();
+        let (super_idx, super_rem) = i.div_rem(&layout.superblock_size());
+        let block_idx = super_rem / layout.block_size();
+        let start = self.rank.data.as_ptr().wrapping_add(super_idx * layout.cacheline_size());
+        prefetch(start);
+        prefetch(start.wrapping_add((layout.superblock_bits() + block_idx * layout.block_bits()) / 8));
+        self.bits.prefetch_bit(i - super_rem % layout.block_size());
+        self.bits.prefetch_bit(i);
+    }
+
     // rank 0 or rank 1
     fn generic_rank(&self, i: usize, value: u32) -> usize {
         let r = self._rank1(i);
@@ -222,38 +644,55 @@ impl<Parameters: RASBVecParameters> FastRASBVec<Parameters> where [CacheBlock; P
     // count number of bits with given `value` before superblock `b`
     fn value_count_before_sblock(&self, sb: usize, value: u32) -> usize {
         if value == 1 {
-            self.rank.superblocks[sb].superblock()
+            self.rank.superblock(sb).superblock()
         } else {
-            sb * Parameters::SUPERBLOCK_SIZE - self.rank.superblocks[sb].superblock()
+            sb * self.layout().superblock_size() - self.rank.superblock(sb).superblock()
         }
     }
 
     // count number of bits with given `value` before block `b` in its superblock `sb`.
     fn value_count_before_block(&self, sb: usize, b: usize, value: u32) -> usize {
         if value == 1 {
-            self.rank.superblocks[sb].block(b) as usize
+            self.rank.superblock(sb).block(b) as usize
         } else {
-            b * Parameters::BLOCK_SIZE - self.rank.superblocks[sb].block(b) as usize
+            b * self.layout().block_size() - self.rank.superblock(sb).block(b) as usize
         }
     }
 
-    fn generic_select(&self, i: usize, value: u32) -> Option<usize> {
-        if i == 0 {
-            return None;
-        }
+    // Find the superblock which contains the i-th bit with `value` through the megablocks.
+    fn find_superblock(&self, i: usize, value: u32) -> usize {
+        let layout = self.layout();
 
-        let total = if value == 0 { &self.count0 } else { &self.count1 };
-        if i > *total {
-            return None
+        // Step 1: binary search over megablocks => find megablock which contains target bit
+        let mega_l = match &self.eytzinger_megablocks {
+            // The first megablock with at least i such bits before it is after the target one. After
+            // push() the Eytzinger megablocks may only cover a prefix of the megablocks, if the target
+            // one is not in it only the sorted megablocks from the last covered one on are searched.
+            Some(eytzinger) => match eytzinger[value as usize].lower_bound(i) {
+                after if after < eytzinger[value as usize].len() || after == self.megablocks.len() => after - 1,
+                covered => self.find_megablock(i, value, covered.saturating_sub(1), self.megablocks.len()),
+            },
+            None => self.find_megablock(i, value, 0, self.megablocks.len()),
+        };
+
+        // Do a linear search within the superblocks in the megablock.
+        let lsblock = mega_l * layout.megablock_factor();
+        let mut rsblock = std::cmp::min(lsblock + layout.megablock_factor(), self.rank.len());
+        while self.value_count_before_sblock(rsblock - 1, value) >= i {
+            rsblock -= 1;
         }
 
-        // Step 1: binary search over megablocks => find megablock which contains target bit
-        let mut mega_l = 0usize;
-        let mut mega_r = self.megablocks.len();
+        rsblock - 1
+    }
+
+    // Binary search over the sorted megablocks [mega_l, mega_r) for the megablock which contains the
+    // i-th bit with `value`. There must be less than i such bits before mega_l.
+    fn find_megablock(&self, i: usize, value: u32, mut mega_l: usize, mut mega_r: usize) -> usize {
+        let layout = self.layout();
         while mega_r - mega_l > 1 {
             let mid = (mega_l + mega_r) / 2;
             let before = if value == 0 {
-                    mid * Parameters::MEGABLOCK_FACTOR * Parameters::SUPERBLOCK_SIZE - self.megablocks[mid]
+                    mid * layout.megablock_factor() * layout.superblock_size() - self.megablocks[mid]
                 }
                 else {
                     self.megablocks[mid]
@@ -265,22 +704,60 @@ impl<Parameters: RASBVecParameters> FastRASBVec<Parameters> where [CacheBlock; P
                 mega_l = mid;
             }
         }
+        mega_l
+    }
 
-        // Do a linear search within the superblocks in the megablock.
-        let lsblock = mega_l * Parameters::MEGABLOCK_FACTOR;
-        let mut rsblock = std::cmp::min(lsblock + Parameters::MEGABLOCK_FACTOR, self.rank.superblocks.len());
-        while self.value_count_before_sblock(rsblock - 1, value) >= i {
-            rsblock -= 1;
+    // Find the superblock which contains the i-th bit with `value` through the select samples: it is
+    // between the superblocks of the samples before and after the bit.
+    fn find_superblock_sampled(&self, i: usize, value: u32) -> usize {
+        let samples = &self.select_samples[value as usize];
+        let j = (i - 1) / self.layout().select_sample_rate();
+        let mut l = samples[j];
+        let mut r = samples.get(j + 1).copied().unwrap_or(self.rank.len() - 1);
+
+        // Binary search for the last superblock with less than i such bits before it.
+        while l < r {
+            let mid = (l + r).div_ceil(2);
+            if self.value_count_before_sblock(mid, value) < i {
+                l = mid;
+            } else {
+                r = mid - 1;
+            }
         }
+        l
+    }
 
-        let start_sblock = rsblock - 1;
-        let start = start_sblock * Parameters::SUPERBLOCK_SIZE;
+    fn generic_select(&self, i: usize, value: u32) -> Option<usize> {
+        if i == 0 {
+            return None;
+        }
+
+        let total = if value == 0 { &self.count0 } else { &self.count1 };
+        if i > *total {
+            return None
+        }
+
+        let layout = self.layout();
+        let start_sblock = if layout.select_sample_rate() > 0 {
+            self.find_superblock_sampled(i, value)
+        } else {
+            self.find_superblock(i, value)
+        };
+
+        let start = start_sblock * layout.superblock_size();
         let in_superblock = i - self.value_count_before_sblock(start_sblock, value);
 
         // Manually search for the correct block in the superblock where our match is.
         // The blocks should already be in the cache so this should be fast.
+        // Blocks after the end of the bit vector are not up to date after push().
+        let last_block = if start_sblock + 1 == self.rank.len() {
+            (self.size() - 1 - start) / layout.block_size()
+        } else {
+            layout.blocks_per_superblock() - 1
+        };
+
         let mut b = 0;
-        while b < (Self::blocks_per_superblock() - 1) {
+        while b < last_block {
             let up_to_block = self.value_count_before_block(start_sblock, b+1, value);
             if up_to_block >= in_superblock {
                 break;
@@ -289,13 +766,19 @@ impl<Parameters: RASBVecParameters> FastRASBVec<Parameters> where [CacheBlock; P
         }
 
         // Final step: manually search for the fitting bit inside the target block.
-        return self.bits.find_nth_x(start + b * Parameters::BLOCK_SIZE,
+        return self.bits.find_nth_x(start + b * layout.block_size(),
             in_superblock - self.value_count_before_block(start_sblock, b, value), value);
     }
 }
 
+impl<Layout: RASBVecLayout> Extend<u32> for RASBVec<Layout> {
+    fn extend<I: IntoIterator<Item = u32>>(&mut self, bits: I) {
+        bits.into_iter().for_each(|bit| self.push(bit));
+    }
+}
+
 // Adapter for the RankSelectVector trait
-impl<Parameters: RASBVecParameters> RankSelectVector for FastRASBVec<Parameters> where [CacheBlock; Parameters::CACHELINE_SIZE]: Sized {
+impl<Layout: RASBVecLayout> RankSelectVector for RASBVec<Layout> {
     fn new(bits: BitVector) -> Self {
         let mut vec = Self::new_empty();
         vec.initialize_for(bits);
@@ -318,12 +801,58 @@ impl<Parameters: RASBVecParameters> RankSelectVector for FastRASBVec<Parameters>
         self.bits.access(i)
     }
 
-    fn get_memory_usage(&self) -> usize {
-        self.megablocks.len() * std::mem::size_of::<usize>() +
-            self.rank.superblocks.len() * std::mem::size_of::<RankSuperblock<Parameters>>()
+    // For rank, the RankSuperblock bytes with the superblock and the block value, and the first and
+    // last BitCell which are counted. Count, next, prev and select_from start with one or two ranks.
+    // The position of a select query is only known after the search, so nothing is prefetched for it.
+    #[inline(always)]
+    fn prefetch(&self, q: &Query) {
+        match *q {
+            Query::Access(i) => self.bits.prefetch_bit(i),
+            Query::Rank0(i) | Query::Rank1(i) => self.prefetch_rank(i),
+            Query::Count(l, r) => {
+                self.prefetch_rank(l);
+                self.prefetch_rank(r);
+            },
+            Query::Next0(p) | Query::Next1(p) | Query::Prev0(p) | Query::Prev1(p) |
+            Query::SelectFrom0(p, _) | Query::SelectFrom1(p, _) => self.prefetch_rank(p),
+            Query::Select0(_) | Query::Select1(_) => {},
+        }
+    }
+
+    // The answer is often in the BitCell of p, only otherwise the directory is used.
+    fn next(&self, x: u32, p: usize) -> Option<usize> {
+        assert!(p <= self.bits.size());
+        if p == self.bits.size() {
+            return None;
+        }
+        if let Some(i) = self.bits.next_x_in_cell(x, p) {
+            return Some(i);
+        }
+
+        let end = (p / BIT_CELL_SIZE + 1) * BIT_CELL_SIZE;
+        if end >= self.bits.size() {
+            return None;
+        }
+        self.generic_select(self.generic_rank(end, x) + 1, x)
     }
 
+    fn prev(&self, x: u32, p: usize) -> Option<usize> {
+        assert!(p < self.bits.size());
+        if let Some(i) = self.bits.prev_x_in_cell(x, p) {
+            return Some(i);
+        }
+
+        match self.generic_rank(p - p % BIT_CELL_SIZE, x) {
+            0 => None,
+            r => self.generic_select(r, x),
+        }
+    }
 
+    fn get_memory_usage(&self) -> usize {
+        (self.megablocks.len() + self.select_samples[0].len() + self.select_samples[1].len()) * std::mem::size_of::<usize>() +
+            self.rank.data.len() * std::mem::size_of::<CacheBlock>() +
+            self.eytzinger_megablocks.iter().flatten().map(Eytzinger::get_memory_usage).sum::<usize>()
+    }
 }
 
 // ---------------------------------------- Unit Tests -------------------------------------------------------
@@ -354,13 +883,15 @@ impl RASBVecParameters for RankBitTestParams {
 mod tests {
     use super::*;
     use crate::tst::*;
+    use crate::query::ExecQueries;
     use rand::{Rng, SeedableRng};
     use rand_xoshiro::Xoshiro256Plus;
 
     #[test]
     fn rank_superblock_test() {
         for test in 0..10 {
-            let mut bfield = RankSuperblock::<RankBitTestParams>::new();
+            let layout = ConstLayout::<RankBitTestParams>::default();
+            let mut bfield = RankSuperblock::new([0; RankBitTestParams::CACHELINE_SIZE + RANK_PADDING], &layout);
             let mut rng = Xoshiro256Plus::seed_from_u64(233 * test);
 
             let sblock = rng.gen_range(0..(1 << RankBitTestParams::SUPERBLOCK_BITS));
@@ -376,9 +907,40 @@ mod tests {
             for i in 0..10 {
                 assert_eq!(bfield.block(i), blocks[i], "block {} not ok", i);
             }
+
+            // The packed format is a little-endian bit stream, and the padding is never written.
+            let bit = |b: usize| ((bfield.data[b / CACHE_BLOCK_BITS] >> (b % CACHE_BLOCK_BITS)) & 1) as usize;
+            for k in 0..RankBitTestParams::SUPERBLOCK_BITS {
+                assert_eq!(bit(k), (sblock >> k) & 1, "superblock bit {}", k);
+            }
+            for i in 0..10 {
+                for k in 0..RankBitTestParams::BLOCK_BITS {
+                    let b = RankBitTestParams::SUPERBLOCK_BITS + i * RankBitTestParams::BLOCK_BITS + k;
+                    assert_eq!(bit(b), ((blocks[i] >> k) & 1) as usize, "block {} bit {}", i, k);
+                }
+            }
+            assert!(bfield.data[RankBitTestParams::CACHELINE_SIZE..].iter().all(|x| *x == 0));
+
+            // Overwriting a value does not change its neighbours.
+            bfield.set_block(3, 0);
+            bfield.set_block(4, (1 << RankBitTestParams::BLOCK_BITS) - 1);
+            assert_eq!(bfield.block(2), blocks[2]);
+            assert_eq!(bfield.block(3), 0);
+            assert_eq!(bfield.block(4), (1 << RankBitTestParams::BLOCK_BITS) - 1);
+            assert_eq!(bfield.block(5), blocks[5]);
+            assert_eq!(bfield.superblock(), sblock);
         }
     }
 
+    #[test]
+    fn rank_superblock_64_bit_superblock() {
+        let layout = RankSelectConfig::new(1, 1, 1, 64).unwrap();
+        let mut bfield = RankSuperblock::new(vec![0xFF; layout.cacheline_size() + RANK_PADDING], &layout);
+        bfield.set_super(0x0123_4567_89AB_CDEF);
+        bfield.set_block(0, 0);
+        assert_eq!((bfield.superblock(), bfield.block(0)), (0x0123_4567_89AB_CDEF, 0));
+    }
+
     #[test]
     fn rank_simple() {
         let bits = "1111111111111111111111";
@@ -395,10 +957,14 @@ mod tests {
         test_simple_select::<FastRASBVec<SmallRASB>>();
     }
 
-    fn test_generic<Parameters: RASBVecParameters>(size: usize, nr_queries: usize, seed: u64) where [CacheBlock; Parameters::CACHELINE_SIZE]: Sized {
+    fn test_generic<Parameters: RASBVecParameters>(size: usize, nr_queries: usize, seed: u64) {
+        test_generic_with(size, nr_queries, seed, FastRASBVec::<Parameters>::new);
+    }
+
+    fn test_generic_with<V: RankSelectVector>(size: usize, nr_queries: usize, seed: u64, build: impl FnOnce(BitVector) -> V) {
         let bits = generate_random_bits_string(size, seed, 0.5);
         println!("{}", bits);
-        let rasb = FastRASBVec::<Parameters>::new(BitVector::new_from_string(bits.as_str()));
+        let rasb = build(BitVector::new_from_string(bits.as_str()));
         let slowb = BitVector::new_from_string(bits.as_str());
         let queries = generate_random_queries(nr_queries, 1, size, None);
         //for q in &queries {
@@ -425,6 +991,171 @@ mod tests {
         test_generic::<BigRASB>(n, q, 3);
     }
 
+    #[test]
+    fn test_runtime_config() {
+        let small = RankSelectConfig::of::<SmallRASB>().unwrap();
+        test_generic_with(35, 30, 1, |bits| RuntimeRASBVec::with_config(bits, small).unwrap());
+
+        let big = RankSelectConfig::new(256, 1024, 3, 20).unwrap();
+        let n = 1024 * 7 + 3 * 256 - 1;
+        test_generic_with(n, n * 2, 3, |bits| RuntimeRASBVec::with_config(bits, big).unwrap());
+
+        let tiny = RankSelectConfig::new(4, 8, 1, 4).unwrap();
+        let res = RuntimeRASBVec::with_config(BitVector::new_from_string(&"1".repeat(16)), tiny);
+        assert!(matches!(res, Err(ConfigError::TooManyBits { .. })));
+    }
+
+    // Push the bits of a random string one by one (starting with the first `prefix` of them built
+    // statically), and compare all queries with a statically built vector after every step.
+    fn test_push_with<Layout: RASBVecLayout>(size: usize, prefix: usize, seed: u64, build: impl Fn(BitVector) -> RASBVec<Layout>) {
+        let bits = generate_random_bits_string(size, seed, 0.5);
+        let mut rasb = build(BitVector::new_from_string(&bits[..prefix]));
+
+        for (step, bit) in bits[prefix..].bytes().enumerate() {
+            rasb.push((bit - b'0') as u32);
+            let n = prefix + step + 1;
+            if step % 7 != 0 && n != size {
+                continue;
+            }
+
+            let expected = build(BitVector::new_from_string(&bits[..n]));
+            assert_eq!(rasb.size(), n);
+            assert_eq!(rasb.megablocks, expected.megablocks);
+            for i in 0..=n + 1 {
+                if i <= n {
+                    assert_eq!(rasb.rank(i), expected.rank(i), "rank({}) with size {}", i, n);
+                }
+                if i < n {
+                    assert_eq!(rasb.access(i), expected.access(i));
+                }
+                assert_eq!(rasb.select0(i), expected.select0(i), "select0({}) with size {}", i, n);
+                assert_eq!(rasb.select1(i), expected.select1(i), "select1({}) with size {}", i, n);
+            }
+        }
+    }
+
+    #[test]
+    fn push() {
+        test_push_with(300, 0, 5, FastRASBVec::<SmallRASB>::new);
+        test_push_with(300, 37, 6, FastRASBVec::<SmallRASB>::new);
+        test_push_with(3000, 1100, 7, FastRASBVec::<BigRASB>::new);
+        test_push_with(300, 20, 5, FastRASBVec::<Params<4, 8, 2, 40, 3>>::new);
+
+        let config = RankSelectConfig::new(64, 256, 2, 20).unwrap();
+        test_push_with(2000, 0, 8, |bits| RuntimeRASBVec::with_config(bits, config).unwrap());
+        test_push_with(2000, 500, 8, |bits| RuntimeRASBVec::with_config(bits, config.with_select_sample_rate(50)).unwrap());
+        test_push_with(2000, 300, 9, |bits| RuntimeRASBVec::with_config(bits, config.with_eytzinger_megablocks(true)).unwrap());
+        // Many megablocks, so that the Eytzinger megablocks often cover only a prefix of them.
+        test_push_with(300, 37, 10, FastRASBVec::<Params<4, 8, 1, 40, 0, true>>::new);
+    }
+
+    // The parallel build must give exactly the same structure as the sequential one.
+    fn check_parallel<Layout: RASBVecLayout + Send>(sequential: impl Fn(BitVector) -> RASBVec<Layout>,
+                                                    parallel: impl Fn(BitVector, usize) -> RASBVec<Layout>, size: usize, seed: u64) {
+        let bits = BitVector::generate_random(size, seed);
+        let expected = sequential(bits.clone());
+        for threads in [1, 2, 3, 7, 64] {
+            let v = parallel(bits.clone(), threads);
+            assert_eq!(v.rank.data[..], expected.rank.data[..], "size={} threads={}", size, threads);
+            assert_eq!(v.megablocks, expected.megablocks);
+            assert_eq!(v.select_samples, expected.select_samples);
+            assert_eq!((v.count0, v.count1), (expected.count0, expected.count1));
+        }
+    }
+
+    #[test]
+    fn new_parallel() {
+        for size in [0, 1, 7, 8, 100, 1000] {
+            check_parallel(FastRASBVec::<SmallRASB>::new, FastRASBVec::<SmallRASB>::new_parallel, size, size as u64);
+            check_parallel(FastRASBVec::<Params<4, 8, 3, 40, 5>>::new, FastRASBVec::<Params<4, 8, 3, 40, 5>>::new_parallel, size, size as u64);
+        }
+        check_parallel(FastRASBVec::<BigRASB>::new, FastRASBVec::<BigRASB>::new_parallel, 100000, 3);
+
+        let config = RankSelectConfig::new(64, 512, 3, 33).unwrap();
+        check_parallel(|bits| RuntimeRASBVec::with_config(bits, config).unwrap(),
+                       |bits, threads| RuntimeRASBVec::with_config_parallel(bits, config, threads).unwrap(), 20000, 5);
+    }
+
+    #[test]
+    fn eytzinger_megablocks() {
+        test_generic::<Params<4, 8, 1, 40, 0, true>>(100, 300, 2);
+        test_generic::<Params<4, 8, 3, 40, 0, true>>(1000, 3000, 3);
+        test_generic::<Params<64, 256, 1, 40, 0, true>>(20000, 5000, 4);
+
+        let sparse = generate_random_bits_string(20000, 6, 0.995);
+        let eytzinger = FastRASBVec::<Params<64, 256, 1, 40, 0, true>>::new(BitVector::new_from_string(&sparse));
+        let plain = FastRASBVec::<Params<64, 256, 1, 40>>::new(BitVector::new_from_string(&sparse));
+        for i in 0..=plain.count1 + 1 {
+            assert_eq!(eytzinger.select1(i), plain.select1(i));
+        }
+        for i in (0..=plain.count0 + 1).step_by(7) {
+            assert_eq!(eytzinger.select0(i), plain.select0(i));
+        }
+        assert!(plain.eytzinger_megablocks.is_none());
+        test_simple_select::<FastRASBVec<Params<4, 8, 2, 40, 0, true>>>();
+    }
+
+    #[test]
+    fn select_samples() {
+        test_generic::<Params<4, 8, 1, 40, 1>>(100, 300, 2);
+        test_generic::<Params<4, 8, 4, 40, 5>>(1000, 3000, 3);
+        test_generic::<Params<256, 1024, 2, 40, 1000>>(12000, 4000, 4);
+        test_generic::<Params<64, 256, 2, 40, 64>>(5000, 5000, 5);
+
+        let sparse = generate_random_bits_string(20000, 6, 0.995);
+        let sampled = FastRASBVec::<Params<64, 256, 2, 40, 3>>::new(BitVector::new_from_string(&sparse));
+        let plain = FastRASBVec::<Params<64, 256, 2, 40>>::new(BitVector::new_from_string(&sparse));
+        for i in 0..=plain.count1 + 1 {
+            assert_eq!(sampled.select1(i), plain.select1(i));
+        }
+        assert_eq!(sampled.select_samples[1].len(), plain.count1.div_ceil(3));
+        assert!(sampled.get_memory_usage() > plain.get_memory_usage());
+        test_simple_select::<FastRASBVec<Params<4, 8, 1, 40, 2>>>();
+    }
+
+    #[test]
+    fn extend() {
+        let bits = generate_random_bits_string(1000, 9, 0.3);
+        let mut rasb = FastRASBVec::<BigRASB>::new_empty();
+        rasb.extend(bits.bytes().map(|b| (b - b'0') as u32));
+
+        let mut plain = BitVector::new_from_string("");
+        plain.extend(bits.bytes().map(|b| (b - b'0') as u32));
+        assert_eq!(plain.words(), BitVector::new_from_string(&bits).words());
+
+        let queries = generate_random_queries(2000, 2, bits.len(), Some(rasb.count1));
+        for ((a, b), q) in queries.iter().exec_queries(&rasb).zip(queries.iter().exec_queries(&plain)).zip(queries.iter()) {
+            assert_eq!(a, b, "got {}, expected {} for query {:?}", a, b, q);
+        }
+    }
+
+    fn check_next_prev(b: &impl RankSelectVector, reference: &BitVector) {
+        let n = reference.size();
+        for x in [0, 1] {
+            for p in 0..=n {
+                assert_eq!(b.next(x, p), reference.next(x, p), "next({}, {}) n = {}", x, p, n);
+                for k in [1, 2, 65] {
+                    assert_eq!(b.select_from(x, p, k), reference.select_from(x, p, k), "select_from({}, {}, {}) n = {}", x, p, k, n);
+                }
+                if p < n {
+                    assert_eq!(b.prev(x, p), reference.prev(x, p), "prev({}, {}) n = {}", x, p, n);
+                    assert_eq!(b.count_range(p, n), reference.count_range(p, n));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn next_prev() {
+        // Partial last BitCells, answers in the same and in far away BitCells, and none at all.
+        for (n, weight0, seed) in [(1, 0.5, 1), (64, 0.5, 2), (1000, 0.5, 3), (1000, 0.99, 4), (1000, 0.0, 5), (1000, 1.0, 6), (3000, 0.999, 7)] {
+            let bv = BitVector::new_from_string(&generate_random_bits_string(n, seed, weight0));
+            check_next_prev(&FastRASBVec::<SmallRASB>::new(bv.clone()), &bv);
+            check_next_prev(&FastRASBVec::<Params<4, 8, 1, 40, 1>>::new(bv.clone()), &bv);
+            check_next_prev(&FastRASBVec::<DefaultParams>::new(bv.clone()), &bv);
+        }
+    }
+
     #[test]
     fn sample_1() {
         test_sample::<FastRASBVec<BigRASB>>();
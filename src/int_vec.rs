@@ -0,0 +1,102 @@
+// A vector of unsigned integers which all have the same (small) number of bits, packed tightly
+// into 64-bit words. Used for the lower bits of the Elias-Fano encoding.
+#[derive(Clone, Debug)]
+pub struct IntVector {
+    data: Vec<u64>,
+    width: usize,
+    len: usize,
+}
+
+impl IntVector {
+    pub fn new(width: usize) -> Self {
+        Self::with_capacity(width, 0)
+    }
+
+    pub fn with_capacity(width: usize, capacity: usize) -> Self {
+        assert!(width <= 64, "IntVector supports at most 64 bits per value");
+        IntVector {
+            data: Vec::with_capacity((capacity * width).div_ceil(64)),
+            width,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    fn mask(&self) -> u64 {
+        if self.width == 64 { u64::MAX } else { (1u64 << self.width) - 1 }
+    }
+
+    // Append a value, only its lowest `width` bits are stored.
+    pub fn push(&mut self, value: u64) {
+        let value = value & self.mask();
+        let start = self.len * self.width;
+        let (word, offset) = (start / 64, start % 64);
+
+        self.len += 1;
+        if self.len * self.width > self.data.len() * 64 {
+            self.data.push(0);
+        }
+
+        if self.width == 0 {
+            return;
+        }
+
+        self.data[word] |= value << offset;
+        if offset + self.width > 64 {
+            self.data[word + 1] |= value >> (64 - offset);
+        }
+    }
+
+    pub fn get(&self, i: usize) -> u64 {
+        assert!(i < self.len);
+        if self.width == 0 {
+            return 0;
+        }
+
+        let start = i * self.width;
+        let (word, offset) = (start / 64, start % 64);
+
+        let mut value = self.data[word] >> offset;
+        if offset + self.width > 64 {
+            value |= self.data[word + 1] << (64 - offset);
+        }
+        value & self.mask()
+    }
+
+    pub fn get_memory_usage(&self) -> usize {
+        self.data.len() * std::mem::size_of::<u64>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand_xoshiro::Xoshiro256Plus;
+
+    #[test]
+    fn push_get() {
+        for width in [0, 1, 5, 13, 32, 63, 64] {
+            let mut rng = Xoshiro256Plus::seed_from_u64(width as u64);
+            let mut v = IntVector::new(width);
+            let values = (0..300).map(|_| rng.gen::<u64>() & v.mask()).collect::<Vec<_>>();
+            values.iter().for_each(|x| v.push(*x));
+
+            assert_eq!(v.len(), values.len());
+            for (i, x) in values.iter().enumerate() {
+                assert_eq!(v.get(i), *x, "width = {}, i = {}", width, i);
+            }
+        }
+    }
+}
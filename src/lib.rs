@@ -0,0 +1,59 @@
+#![allow(dead_code)]
+#![allow(clippy::needless_return)]
+#![allow(clippy::needless_range_loop)]
+
+//! Static bit vectors with support for rank and select queries.
+//!
+//! The main entry point is [`FastRASBVec`], which is built from a plain [`BitVector`] and answers
+//! queries through the [`RankSelectVector`] trait. Queries can also be described with [`Query`]
+//! and answered in bulk with [`ExecQueries`].
+
+pub mod ascii;
+pub mod broadword;
+pub mod bvec;
+pub mod config;
+pub mod dynamic_bvec;
+pub mod elias_fano;
+pub mod eytzinger;
+pub mod fast_bvec;
+pub mod int_vec;
+pub mod parser;
+pub mod persist;
+pub mod popcount;
+pub mod poppy;
+pub mod rrr;
+pub mod storage;
+pub mod query;
+pub mod rank9;
+pub mod wavelet_matrix;
+
+#[cfg(any(test, feature = "tst"))]
+pub mod tst;
+
+#[cfg(feature = "benchmark")]
+pub mod benchmark;
+
+pub use bvec::{BitVector, RankSelectVector};
+pub use config::{ConfigError, RankSelectConfig};
+pub use dynamic_bvec::DynamicBitVector;
+pub use elias_fano::EliasFanoVec;
+pub use fast_bvec::{ConstLayout, DefaultParams, FastRASBVec, Params, RASBVec, RASBVecLayout, RASBVecParameters, RuntimeRASBVec};
+pub use int_vec::IntVector;
+pub use parser::{ParseError, ParseErrorReason};
+pub use poppy::PoppyVec;
+pub use query::{exec_one_query, ExecBatch, ExecQueries, Query};
+pub use rank9::Rank9Vec;
+pub use rrr::RRRVec;
+pub use wavelet_matrix::WaveletMatrix;
+
+// Measure the time needed to run the given block, in milliseconds.
+#[macro_export]
+macro_rules! measure_time {
+    ($block:block) => {
+        {
+            let start = std::time::Instant::now();
+            $block
+            start.elapsed().as_millis()
+        }
+    }
+}
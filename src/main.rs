@@ -1,97 +1,175 @@
-#![feature(impl_trait_in_assoc_type)]
-#![feature(trait_alias)]
-#![feature(int_roundings)]
-#![allow(incomplete_features)]
-#![feature(generic_const_exprs)]
-#![allow(dead_code)]
-#![allow(stable_features)]
-#![feature(option_take_if)]
-
-
-mod bvec;
-mod tst;
-mod fast_bvec;
-mod benchmark;
-
-use tst::Query;
-
-use crate::benchmark::*;
-use crate::tst::ExecQueries;
-use crate::bvec::RankSelectVector;
+use bitvec::parser::{parse_input_as, write_input, BitsFormat, Input};
+use bitvec::{measure_time, DefaultParams, ExecBatch, FastRASBVec, Query, RankSelectConfig, RankSelectVector, RuntimeRASBVec};
 use std::io::Write;
-use std::io::BufRead;
 
-fn praktikum_main() {
+struct Options {
+    input: String,
+    output: String,
+    // Layout chosen at runtime, if not given the compile-time DefaultParams are used.
+    config: Option<RankSelectConfig>,
+    // Number of threads for building the structure and answering the queries.
+    threads: usize,
+    // Format of the bit vector in the input file.
+    format: BitsFormat,
+    // If given, the input file is only converted to this format and written to the output.
+    convert: Option<BitsFormat>,
+}
+
+fn parse_args() -> Options {
     let args = std::env::args().collect::<Vec<_>>();
-    if args.len() != 3 {
-        panic!("Usage: {} <input> <output>", args[0]);
+    let usage = format!("Usage: {} <input> <output> [--config <file>] [--layout <key=value,...>] [--threads <n>] [--format <f>] [--convert <f>], formats: ascii, hex, packed, positions", args[0]);
+
+    let mut positional = vec![];
+    let mut config = None;
+    let mut threads = 1;
+    let mut format = BitsFormat::Ascii;
+    let mut convert = None;
+    let mut it = args.iter().skip(1);
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--config" => {
+                let path = it.next().unwrap_or_else(|| fail(&usage));
+                let text = std::fs::read_to_string(path).unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+                config = Some(text.parse::<RankSelectConfig>().unwrap_or_else(|e| fail(&format!("{}: {}", path, e))));
+            },
+            "--layout" => {
+                let spec = it.next().unwrap_or_else(|| fail(&usage));
+                config = Some(spec.parse::<RankSelectConfig>().unwrap_or_else(|e| fail(&format!("--layout: {}", e))));
+            },
+            "--threads" => {
+                let n = it.next().unwrap_or_else(|| fail(&usage));
+                threads = n.parse::<usize>().ok().filter(|t| *t > 0).unwrap_or_else(|| fail(&format!("--threads must be a positive number, got {:?}", n)));
+            },
+            "--format" => {
+                format = it.next().unwrap_or_else(|| fail(&usage)).parse().unwrap_or_else(|e| fail(&format!("--format: {}", e)));
+            },
+            "--convert" => {
+                convert = Some(it.next().unwrap_or_else(|| fail(&usage)).parse().unwrap_or_else(|e| fail(&format!("--convert: {}", e))));
+            },
+            _ => positional.push(arg.clone()),
+        }
     }
 
-    let input = &args[1];
-    let output = &args[2];
-
-    let bv;
-    let mut qs;
-
-    {
-        let input = std::fs::File::open(input).unwrap();
-        let mut file = std::io::BufReader::new(input);
-
-        let mut n_str = String::new();
-        file.read_line(&mut n_str).ok();
-        let n = n_str.trim().parse::<usize>().unwrap();
-
-        bv = bvec::BitVector::new_from_input(&mut file);
-        qs = Vec::with_capacity(n);
-        file.lines().map(|x| x.unwrap()).for_each(|line| {
-            let mut line = line.trim().split(' ');
-            let cmd = line.next().unwrap();
-            let v = line.next().unwrap().parse::<usize>().unwrap();
-
-            qs.push(match cmd {
-                "access" => Query::Access(v),
-                "rank" => if v == 0 {
-                    Query::Rank0(line.next().unwrap().parse::<usize>().unwrap())
-                } else {
-                    Query::Rank1(line.next().unwrap().parse::<usize>().unwrap())
-                },
-                "select" => if v == 0 {
-                    Query::Select0(line.next().unwrap().parse::<usize>().unwrap())
-                } else {
-                    Query::Select1(line.next().unwrap().parse::<usize>().unwrap())
-                },
-                _ => panic!("Unknown query type encounted in the input file!")
-            });
-        });
+    if positional.len() != 2 {
+        fail(&usage);
+    }
+
+    Options {
+        output: positional.pop().unwrap(),
+        input: positional.pop().unwrap(),
+        config,
+        threads,
+        format,
+        convert,
     }
+}
 
+// Build the rank/select structure and answer all queries with it. The queries are split into
+// `threads` consecutive chunks which are answered concurrently, the structure is immutable and shared
+// by all threads.
+// Returns the answers (in the order of the queries), build time, query time (wall clock), query time
+// of every thread and the space used by the structure.
+fn run_queries<V: RankSelectVector + Sync>(build: impl FnOnce() -> V, qs: &[Query], threads: usize) -> (Vec<usize>, u128, u128, Vec<u128>, usize) {
     let used_space;
-    let mut answers: Vec<usize> = vec![0; qs.len()];
+    let mut answers = Vec::with_capacity(qs.len());
+    let mut thread_times = vec![];
     let accel_bv;
 
     let time_build = measure_time!({
-        accel_bv = fast_bvec::FastRASBVec::<Params<4096, 32768, 32, 48>>::new(bv);
+        accel_bv = build();
     });
 
     let time_query = measure_time!({
         used_space = accel_bv.get_memory_usage();
-        for (i, q) in qs.iter().exec_queries(&accel_bv).enumerate() {
-            answers[i] = q;
+        let accel_bv = &accel_bv;
+        std::thread::scope(|s| {
+            let handles = qs.chunks(qs.len().div_ceil(threads).max(1)).map(|chunk| {
+                s.spawn(move || {
+                    let chunk_answers;
+                    let time = measure_time!({
+                        chunk_answers = accel_bv.exec_batch(chunk);
+                    });
+                    (chunk_answers, time)
+                })
+            }).collect::<Vec<_>>();
+
+            for handle in handles {
+                let (chunk_answers, time) = handle.join().unwrap();
+                answers.extend(chunk_answers);
+                thread_times.push(time);
+            }
+        });
+    });
+
+    (answers, time_build, time_query, thread_times, used_space)
+}
+
+// Print an error and exit with a non-zero exit code.
+fn fail(message: &str) -> ! {
+    eprintln!("error: {}", message);
+    std::process::exit(1);
+}
+
+fn praktikum_main() {
+    let options = parse_args();
+    let input = &options.input;
+    let output = &options.output;
+
+    // "-" is stdin for the input and stdout for the output.
+    let parsed;
+    let time_parse = measure_time!({
+        parsed = if input == "-" {
+            parse_input_as(&mut std::io::stdin().lock(), options.format)
+        } else {
+            let file = std::fs::File::open(input).unwrap_or_else(|e| fail(&format!("{}: {}", input, e)));
+            parse_input_as(&mut std::io::BufReader::new(file), options.format)
         };
     });
+    let parsed = parsed.unwrap_or_else(|e| fail(&format!("{}:{}", if input == "-" { "<stdin>" } else { input }, e)));
+
+    let out: Box<dyn Write> = if output == "-" {
+        Box::new(std::io::stdout().lock())
+    } else {
+        Box::new(std::fs::File::create(output).unwrap_or_else(|e| fail(&format!("{}: {}", output, e))))
+    };
+    let mut out = std::io::BufWriter::new(out);
+
+    if let Some(format) = options.convert {
+        write_input(&mut out, &parsed, format).and_then(|_| out.flush()).unwrap_or_else(|e| fail(&format!("{}: {}", output, e)));
+        return;
+    }
+    let Input { bits: bv, queries: qs } = parsed;
+
+    let threads = options.threads;
+    // The layout is checked against the bit vector before the build, so that the build cannot fail.
+    if let Some(config) = &options.config {
+        config.check_size(bv.size()).unwrap_or_else(|e| fail(&e.to_string()));
+    }
+    let (answers, time_build, time_query, thread_times, used_space) = match options.config {
+        Some(config) => run_queries(|| RuntimeRASBVec::with_config_parallel(bv, config, threads).unwrap(), &qs, threads),
+        None => run_queries(|| FastRASBVec::<DefaultParams>::new_parallel(bv, threads), &qs, threads),
+    };
 
     // Write to output, one query per line
-    let mut out = std::fs::File::create(output).unwrap();
     for answer in answers {
-        writeln!(out, "{}", answer).unwrap();
+        writeln!(out, "{}", answer).unwrap_or_else(|e| fail(&format!("{}: {}", output, e)));
+    }
+    out.flush().unwrap_or_else(|e| fail(&format!("{}: {}", output, e)));
+    drop(out);
+
+    // If the answers go to stdout, the RESULT line goes to stderr so that it does not mix with them.
+    let thread_times = thread_times.iter().map(|t| t.to_string()).collect::<Vec<_>>().join(",");
+    let result = format!("RESULT name=Ilia_Bozhinov time_parse={} time_build={} time_query={} space={} threads={} time_query_threads={}",
+        time_parse, time_build, time_query, used_space*8, threads, thread_times);
+    if output == "-" {
+        eprintln!("{}", result);
+    } else {
+        println!("{}", result);
     }
-
-    println!("RESULT name=Ilia_Bozhinov time_build={} time_query={} space={}", time_build, time_query, used_space*8);
-    return;
 }
 
 fn main() {
     praktikum_main();
-    //benchmark_select_all(&[AllBench::Random, AllBench::RankGeneral, AllBench::SelectGeneral, AllBench::SelectBruteforce]);
-    //benchmark_select_all(&[AllBench::RankGeneral]);
+    //bitvec::benchmark::benchmark_select_all(&[AllBench::Random, AllBench::RankGeneral, AllBench::SelectGeneral, AllBench::SelectBruteforce]);
+    //bitvec::benchmark::benchmark_select_all(&[AllBench::RankGeneral]);
 }
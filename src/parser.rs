@@ -0,0 +1,838 @@
+use crate::ascii::{pack_ascii_word, ASCII_WORD};
+use crate::bvec::{BitCell, BitVector, BIT_CELL_SIZE};
+use crate::query::Query;
+use std::fmt;
+use std::io::{BufRead, Read, Write};
+use std::str::FromStr;
+
+// Parser for the input files of the main program:
+//
+//   <N: number of queries>
+//   <bit vector, in one of the BitsFormats>
+//   <N queries, one per line: access P | rank 0|1 P | select 0|1 P | count L R | next 0|1 P |
+//    prev 0|1 P | select_from 0|1 P K>
+//
+// Lines may end with \n or \r\n, and the last line does not need a line ending. Empty query lines
+// are skipped. Every malformed input is reported as a ParseError with the (1-based) line and column
+// where the problem was found.
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub reason: ParseErrorReason,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseErrorReason {
+    // The input ended before the given part of it.
+    UnexpectedEof(&'static str),
+    // A character other than 0 and 1 in the bit vector.
+    InvalidBit(char),
+    // A query which is not one of the known commands.
+    UnknownCommand(String),
+    // The given argument of a query is missing.
+    MissingArgument(&'static str),
+    // A number (count or position) which cannot be parsed.
+    InvalidNumber(String),
+    // The bit value of a rank or select query is not 0 or 1.
+    InvalidBitValue(String),
+    // More arguments than the query takes.
+    TrailingInput(String),
+    // The position of a query is past the end of the bit vector.
+    PositionOutOfRange { position: usize, size: usize },
+    // A count query whose start is after its end.
+    InvalidRange { l: usize, r: usize },
+    // A character which is not a hex digit in a hex bit vector.
+    InvalidHexDigit(char),
+    // A hex bit vector of `size` bits with the wrong number of digits.
+    WrongHexLength { size: usize, digits: usize },
+    // A position in a position list which is not greater than the previous one.
+    UnsortedPosition { position: usize, previous: usize },
+    // The memory for a bit vector of the given number of bits cannot be allocated.
+    SizeTooLarge(usize),
+    // Reading the input failed.
+    Io(String),
+}
+
+impl fmt::Display for ParseErrorReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseErrorReason::UnexpectedEof(what) => write!(f, "unexpected end of input, expected {}", what),
+            ParseErrorReason::InvalidBit(c) => write!(f, "invalid character {:?} in the bit vector, expected 0 or 1", c),
+            ParseErrorReason::UnknownCommand(cmd) => write!(f, "unknown query {:?}, expected access, rank, select, count, next, prev or select_from", cmd),
+            ParseErrorReason::MissingArgument(what) => write!(f, "missing {}", what),
+            ParseErrorReason::InvalidNumber(s) => write!(f, "invalid number {:?}", s),
+            ParseErrorReason::InvalidBitValue(s) => write!(f, "invalid bit value {:?}, expected 0 or 1", s),
+            ParseErrorReason::TrailingInput(s) => write!(f, "unexpected {:?} after the query", s),
+            ParseErrorReason::PositionOutOfRange { position, size } =>
+                write!(f, "position {} is out of range for a bit vector with {} bits", position, size),
+            ParseErrorReason::InvalidRange { l, r } => write!(f, "invalid range, start {} is after end {}", l, r),
+            ParseErrorReason::InvalidHexDigit(c) => write!(f, "invalid hex digit {:?}", c),
+            ParseErrorReason::WrongHexLength { size, digits } =>
+                write!(f, "{} bits need {} hex digits, found {}", size, size.div_ceil(4), digits),
+            ParseErrorReason::UnsortedPosition { position, previous } =>
+                write!(f, "position {} is not greater than the previous position {}", position, previous),
+            ParseErrorReason::SizeTooLarge(size) => write!(f, "cannot allocate a bit vector of {} bits", size),
+            ParseErrorReason::Io(e) => write!(f, "read error: {}", e),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.reason)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl ParseError {
+    fn new(line: usize, column: usize, reason: ParseErrorReason) -> Self {
+        ParseError { line, column, reason }
+    }
+
+    fn io(line: usize, e: std::io::Error) -> Self {
+        ParseError::new(line, 1, ParseErrorReason::Io(e.to_string()))
+    }
+}
+
+// The contents of an input file.
+pub struct Input {
+    pub bits: BitVector,
+    pub queries: Vec<Query>,
+}
+
+// Encodings of the bit vector in an input file:
+//
+//   ascii      a line of 0s and 1s, one character per bit.
+//   hex        a line "<number of bits> <hex digits>". Digit k holds the bits 4k..4k+3, the lowest
+//              bit of the digit first, so the digits of a word are in the reverse order of the usual
+//              way to write it as a number.
+//   packed     binary: the number of bits as a little-endian u64, followed by the bits as
+//              little-endian u64 words. The words are not followed by a line ending, but a single \n
+//              after them is accepted.
+//   positions  a line "<number of bits> <p1> <p2> ...", the increasing positions of the set bits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BitsFormat {
+    Ascii,
+    Hex,
+    Packed,
+    Positions,
+}
+
+impl FromStr for BitsFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ascii" => Ok(BitsFormat::Ascii),
+            "hex" => Ok(BitsFormat::Hex),
+            "packed" => Ok(BitsFormat::Packed),
+            "positions" => Ok(BitsFormat::Positions),
+            _ => Err(format!("unknown format {:?}, expected ascii, hex, packed or positions", s)),
+        }
+    }
+}
+
+impl BitsFormat {
+    // Read a bit vector in this format, `line_no` is only used for the errors.
+    pub fn read<R: BufRead>(self, reader: &mut R, line_no: usize) -> Result<BitVector, ParseError> {
+        match self {
+            BitsFormat::Ascii => read_bits(reader, line_no),
+            BitsFormat::Hex => read_bits_hex(reader, line_no),
+            BitsFormat::Packed => {
+                let bits = read_bits_packed(reader, line_no)?;
+                if reader.fill_buf().map_err(|e| ParseError::io(line_no, e))?.first() == Some(&b'\n') {
+                    reader.consume(1);
+                }
+                Ok(bits)
+            },
+            BitsFormat::Positions => read_bits_positions(reader, line_no),
+        }
+    }
+
+    // Write a bit vector in this format, including the line ending of the text formats.
+    pub fn write<W: Write>(self, bits: &BitVector, writer: &mut W) -> std::io::Result<()> {
+        match self {
+            BitsFormat::Ascii => bits.write_ascii(writer),
+            BitsFormat::Hex => bits.write_hex(writer),
+            BitsFormat::Packed => bits.write_packed(writer),
+            BitsFormat::Positions => bits.write_positions(writer),
+        }
+    }
+}
+
+pub fn parse_input<R: BufRead>(reader: &mut R) -> Result<Input, ParseError> {
+    parse_input_as(reader, BitsFormat::Ascii)
+}
+
+// Write an input file which parse_input_as reads back with the same format.
+pub fn write_input<W: Write>(writer: &mut W, input: &Input, format: BitsFormat) -> std::io::Result<()> {
+    writeln!(writer, "{}", input.queries.len())?;
+    format.write(&input.bits, writer)?;
+    if format == BitsFormat::Packed {
+        writeln!(writer)?;
+    }
+    for q in &input.queries {
+        writeln!(writer, "{}", q)?;
+    }
+    Ok(())
+}
+
+pub fn parse_input_as<R: BufRead>(reader: &mut R, format: BitsFormat) -> Result<Input, ParseError> {
+    let mut line = String::new();
+    let n = reader.read_line(&mut line).map_err(|e| ParseError::io(1, e))?;
+    if n == 0 {
+        return Err(ParseError::new(1, 1, ParseErrorReason::UnexpectedEof("the number of queries")));
+    }
+    let (column, count) = tokens(&line).next()
+        .ok_or(ParseError::new(1, 1, ParseErrorReason::MissingArgument("number of queries")))?;
+    let count = parse_number(count, 1, column)?;
+
+    let bits = format.read(reader, 2)?;
+
+    // The count is only a hint, do not trust it with the allocation.
+    let mut queries = Vec::with_capacity(count.min(1 << 20));
+    let mut line_no = 2;
+    loop {
+        line.clear();
+        line_no += 1;
+        if reader.read_line(&mut line).map_err(|e| ParseError::io(line_no, e))? == 0 {
+            break;
+        }
+        if let Some(query) = parse_query(&line, line_no, bits.size())? {
+            queries.push(query);
+        }
+    }
+
+    Ok(Input { bits, queries })
+}
+
+// Read a bit vector in ascii form (0101010011...) until the end of the line or of the input. The
+// input is read in chunks of 64 MiB, so that the line is never held in memory as a whole. `line_no`
+// is only used for the errors.
+pub(crate) fn read_bits<R: BufRead>(reader: &mut R, line_no: usize) -> Result<BitVector, ParseError> {
+    let mut v: Vec<BitCell> = vec![];
+    let mut buf = vec![];
+    let mut size: usize = 0;
+    // Column of a \r which has to be followed by \n (or the end of the input).
+    let mut pending_cr = None;
+    let mut empty = true;
+
+    loop {
+        buf.clear();
+        let n = (&mut *reader).take(CHUNK_SIZE as u64).read_until(b'\n', &mut buf).map_err(|e| ParseError::io(line_no, e))?;
+        if n == 0 {
+            if empty {
+                return Err(ParseError::new(line_no, 1, ParseErrorReason::UnexpectedEof("the bit vector")));
+            }
+            break;
+        }
+        empty = false;
+
+        let line_end = buf.last() == Some(&b'\n');
+        let content = if line_end { &buf[..n - 1] } else { &buf[..] };
+        if let Some(column) = pending_cr {
+            if !content.is_empty() {
+                return Err(ParseError::new(line_no, column, ParseErrorReason::InvalidBit('\r')));
+            }
+        }
+
+        let (content, cr) = match content.split_last() {
+            Some((b'\r', rest)) => (rest, true),
+            _ => (content, false),
+        };
+
+        pack_bits(&mut v, size, content, line_no)?;
+        size += content.len();
+
+        if line_end {
+            break;
+        }
+        pending_cr = if cr { Some(size + 1) } else { None };
+    }
+
+    Ok(BitVector::from_words(v, size))
+}
+
+// Read exactly `len` ascii bits (0101010011...), in chunks of 64 MiB. Nothing after them is read,
+// so this also works for sources without line structure, like a socket.
+pub(crate) fn read_bits_exact<R: Read>(reader: &mut R, len: usize, line_no: usize) -> Result<BitVector, ParseError> {
+    let mut v: Vec<BitCell> = Vec::with_capacity(len.div_ceil(BIT_CELL_SIZE));
+    let mut buf = vec![0; len.min(CHUNK_SIZE)];
+    let mut size = 0;
+
+    while size < len {
+        let chunk = &mut buf[..(len - size).min(CHUNK_SIZE)];
+        reader.read_exact(chunk).map_err(|e| match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => ParseError::new(line_no, size + 1, ParseErrorReason::UnexpectedEof("more bits")),
+            _ => ParseError::io(line_no, e),
+        })?;
+
+        pack_bits(&mut v, size, chunk, line_no)?;
+        size += chunk.len();
+    }
+
+    Ok(BitVector::from_words(v, size))
+}
+
+const CHUNK_SIZE: usize = 1 << 26;
+
+// Read a hex bit vector: "<number of bits> <hex digits>", see BitsFormat.
+pub(crate) fn read_bits_hex<R: BufRead>(reader: &mut R, line_no: usize) -> Result<BitVector, ParseError> {
+    let mut header = Header::default();
+    let mut size = None;
+    let mut v: Vec<BitCell> = vec![];
+    let mut digits = 0;
+    let mut column = 0;
+
+    let nonempty = for_each_line_piece(reader, line_no, |piece| {
+        for c in piece {
+            column += 1;
+            if size.is_none() {
+                size = header.push(*c, column, line_no)?;
+                continue;
+            }
+
+            let digit = (*c as char).to_digit(16).ok_or(ParseError::new(line_no, column, ParseErrorReason::InvalidHexDigit(*c as char)))?;
+            if digits % 16 == 0 {
+                v.push(0);
+            }
+            *v.last_mut().unwrap() |= (digit as BitCell) << (4 * (digits % 16));
+            digits += 1;
+        }
+        Ok(())
+    })?;
+
+    let size = match size {
+        Some(size) => size,
+        None => header.finish(nonempty, line_no)?,
+    };
+    if digits != size.div_ceil(4) {
+        return Err(ParseError::new(line_no, column + 1, ParseErrorReason::WrongHexLength { size, digits }));
+    }
+    Ok(BitVector::from_words(clear_after_end(v, size), size))
+}
+
+// Read a list of set bits: "<number of bits> <p1> <p2> ...", see BitsFormat.
+pub(crate) fn read_bits_positions<R: BufRead>(reader: &mut R, line_no: usize) -> Result<BitVector, ParseError> {
+    let mut header = Header::default();
+    let mut size = None;
+    let mut v: Vec<BitCell> = vec![];
+    let mut previous = None;
+    let mut column = 0;
+
+    let set_bit = |v: &mut Vec<BitCell>, token: &[u8], token_column: usize, size: usize, previous: &mut Option<usize>| {
+        let position = parse_number(std::str::from_utf8(token).unwrap_or("?"), line_no, token_column)?;
+        if position >= size {
+            return Err(ParseError::new(line_no, token_column, ParseErrorReason::PositionOutOfRange { position, size }));
+        }
+        if let Some(previous) = *previous {
+            if position <= previous {
+                return Err(ParseError::new(line_no, token_column, ParseErrorReason::UnsortedPosition { position, previous }));
+            }
+        }
+        *previous = Some(position);
+
+        let (word, bit) = (position / BIT_CELL_SIZE, position % BIT_CELL_SIZE);
+        if v.len() <= word {
+            v.resize(word + 1, 0);
+        }
+        v[word] |= 1 << bit;
+        Ok(())
+    };
+
+    // The current position, at most 20 digits (or one more to report it).
+    let mut token = Vec::with_capacity(21);
+    let mut token_column = 0;
+    let nonempty = for_each_line_piece(reader, line_no, |piece| {
+        for c in piece {
+            column += 1;
+            let Some(size) = size else {
+                size = header.push(*c, column, line_no)?;
+                if let Some(size) = size {
+                    header.reserve_words(&mut v, size, line_no)?;
+                }
+                continue;
+            };
+
+            if *c == b' ' || *c == b'\t' {
+                if !token.is_empty() {
+                    set_bit(&mut v, &token, token_column, size, &mut previous)?;
+                    token.clear();
+                }
+            } else if token.len() <= 20 {
+                if token.is_empty() {
+                    token_column = column;
+                }
+                token.push(*c);
+            }
+        }
+        Ok(())
+    })?;
+
+    let size = match size {
+        Some(size) => size,
+        None => {
+            let size = header.finish(nonempty, line_no)?;
+            header.reserve_words(&mut v, size, line_no)?;
+            size
+        },
+    };
+    if !token.is_empty() {
+        set_bit(&mut v, &token, token_column, size, &mut previous)?;
+    }
+    v.resize(size.div_ceil(BIT_CELL_SIZE), 0);
+    Ok(BitVector::from_words(v, size))
+}
+
+// Read a packed bit vector: the number of bits as a little-endian u64, followed by the little-endian
+// words, see BitsFormat. The words are read in chunks of 64 MiB.
+pub(crate) fn read_bits_packed<R: Read>(reader: &mut R, line_no: usize) -> Result<BitVector, ParseError> {
+    let eof = |what: &'static str| move |e: std::io::Error| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => ParseError::new(line_no, 1, ParseErrorReason::UnexpectedEof(what)),
+        _ => ParseError::io(line_no, e),
+    };
+
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header).map_err(eof("the number of bits"))?;
+    let size = usize::try_from(u64::from_le_bytes(header))
+        .map_err(|_| ParseError::new(line_no, 1, ParseErrorReason::InvalidNumber(u64::from_le_bytes(header).to_string())))?;
+
+    let nr_words = size.div_ceil(BIT_CELL_SIZE);
+    let chunk_words = CHUNK_SIZE / std::mem::size_of::<BitCell>();
+    let mut v: Vec<BitCell> = vec![];
+    let mut buf = vec![0u8; nr_words.min(chunk_words) * std::mem::size_of::<BitCell>()];
+    while v.len() < nr_words {
+        let chunk = &mut buf[..(nr_words - v.len()).min(chunk_words) * std::mem::size_of::<BitCell>()];
+        reader.read_exact(chunk).map_err(eof("more packed words"))?;
+        v.extend(chunk.chunks_exact(8).map(|w| BitCell::from_le_bytes(w.try_into().unwrap())));
+    }
+    Ok(BitVector::from_words(clear_after_end(v, size), size))
+}
+
+fn clear_after_end(mut v: Vec<BitCell>, size: usize) -> Vec<BitCell> {
+    if !size.is_multiple_of(BIT_CELL_SIZE) {
+        *v.last_mut().unwrap() &= (1 << (size % BIT_CELL_SIZE)) - 1;
+    }
+    v
+}
+
+// The "<number of bits> " at the start of the hex and positions formats, read one character at a
+// time.
+#[derive(Default)]
+struct Header {
+    digits: Vec<u8>,
+    column: usize,
+}
+
+impl Header {
+    // Returns the number of bits after the space which ends it.
+    fn push(&mut self, c: u8, column: usize, line_no: usize) -> Result<Option<usize>, ParseError> {
+        if c == b' ' || c == b'\t' {
+            return if self.digits.is_empty() { Ok(None) } else { self.finish(true, line_no).map(Some) };
+        }
+        if self.digits.is_empty() {
+            self.column = column;
+        }
+        if self.digits.len() <= 20 {
+            self.digits.push(c);
+        }
+        Ok(None)
+    }
+
+    // The number of bits if the line ends after it.
+    fn finish(&self, nonempty: bool, line_no: usize) -> Result<usize, ParseError> {
+        if !nonempty {
+            return Err(ParseError::new(line_no, 1, ParseErrorReason::UnexpectedEof("the bit vector")));
+        }
+        if self.digits.is_empty() {
+            return Err(ParseError::new(line_no, 1, ParseErrorReason::MissingArgument("number of bits")));
+        }
+        parse_number(std::str::from_utf8(&self.digits).unwrap_or("?"), line_no, self.column)
+    }
+
+    // Reserve the words for `size` bits. The number of bits is not checked against the input, so the
+    // allocation may fail.
+    fn reserve_words(&self, v: &mut Vec<BitCell>, size: usize, line_no: usize) -> Result<(), ParseError> {
+        v.try_reserve_exact(size.div_ceil(BIT_CELL_SIZE))
+            .map_err(|_| ParseError::new(line_no, self.column, ParseErrorReason::SizeTooLarge(size)))
+    }
+}
+
+// Call `f` with the rest of the current line in pieces, as they are in the buffer of the reader. The
+// line ending (\n or \r\n) is consumed but not passed to `f`. Returns false if the input was already
+// at its end.
+fn for_each_line_piece<R: BufRead>(reader: &mut R, line_no: usize, mut f: impl FnMut(&[u8]) -> Result<(), ParseError>) -> Result<bool, ParseError> {
+    let mut nonempty = false;
+    // A \r at the end of the last piece, which is only content if no \n follows.
+    let mut pending_cr = false;
+
+    loop {
+        let buf = reader.fill_buf().map_err(|e| ParseError::io(line_no, e))?;
+        if buf.is_empty() {
+            return Ok(nonempty);
+        }
+        nonempty = true;
+
+        let (piece, line_end) = match buf.iter().position(|c| *c == b'\n') {
+            Some(p) => (&buf[..p], true),
+            None => (buf, false),
+        };
+        let consumed = piece.len() + line_end as usize;
+
+        if pending_cr && !(piece.is_empty() && line_end) {
+            f(b"\r")?;
+        }
+        pending_cr = piece.last() == Some(&b'\r');
+        f(if pending_cr { &piece[..piece.len() - 1] } else { piece })?;
+
+        reader.consume(consumed);
+        if line_end {
+            return Ok(true);
+        }
+    }
+}
+
+// Append the ascii bits in `content` to `v`, which already contains `size` bits. Whole words are
+// packed (and validated) 64 characters at a time with pack_ascii_word, only the characters which
+// fill up the last word of `v` and the rest at the end are handled one by one.
+fn pack_bits(v: &mut Vec<BitCell>, size: usize, content: &[u8], line_no: usize) -> Result<(), ParseError> {
+    let invalid = |i: usize| ParseError::new(line_no, size + i + 1, ParseErrorReason::InvalidBit(content[i] as char));
+    let pack_one_by_one = |v: &mut Vec<BitCell>, range: std::ops::Range<usize>| {
+        for i in range {
+            let bit = match content[i] {
+                b'0' => 0,
+                b'1' => 1,
+                _ => return Err(invalid(i)),
+            };
+
+            let idx = (size + i) % BIT_CELL_SIZE;
+            if idx == 0 {
+                v.push(0);
+            }
+            *v.last_mut().unwrap() |= (bit as BitCell) << idx;
+        }
+        Ok(())
+    };
+
+    v.reserve(content.len().div_ceil(BIT_CELL_SIZE) + 1);
+    let head = ((BIT_CELL_SIZE - size % BIT_CELL_SIZE) % BIT_CELL_SIZE).min(content.len());
+    pack_one_by_one(v, 0..head)?;
+
+    let mut words = content[head..].chunks_exact(ASCII_WORD);
+    for (k, chars) in (&mut words).enumerate() {
+        match pack_ascii_word(chars.try_into().unwrap()) {
+            Some(word) => v.push(word),
+            None => {
+                let j = chars.iter().position(|c| *c != b'0' && *c != b'1').unwrap();
+                return Err(invalid(head + k * ASCII_WORD + j));
+            },
+        }
+    }
+
+    pack_one_by_one(v, content.len() - words.remainder().len()..content.len())
+}
+
+// Parse one query line. Returns None for empty lines. The positions are checked against `size`,
+// except for select, whose queries for bits which do not exist are answered with usize::MAX.
+pub fn parse_query(line: &str, line_no: usize, size: usize) -> Result<Option<Query>, ParseError> {
+    let line = line.trim_end_matches(['\n', '\r']);
+    let end_column = line.len() + 1;
+    let mut tokens = tokens(line);
+
+    let Some((column, cmd)) = tokens.next() else {
+        return Ok(None);
+    };
+
+    // The number and its column.
+    let number = |tokens: &mut dyn Iterator<Item = (usize, &str)>, what: &'static str| {
+        let (column, token) = tokens.next().ok_or(ParseError::new(line_no, end_column, ParseErrorReason::MissingArgument(what)))?;
+        parse_number(token, line_no, column).map(|n| (n, column))
+    };
+    let bit = |tokens: &mut dyn Iterator<Item = (usize, &str)>| {
+        match tokens.next() {
+            Some((_, "0")) => Ok(false),
+            Some((_, "1")) => Ok(true),
+            Some((column, token)) => Err(ParseError::new(line_no, column, ParseErrorReason::InvalidBitValue(token.to_string()))),
+            None => Err(ParseError::new(line_no, end_column, ParseErrorReason::MissingArgument("bit value"))),
+        }
+    };
+    let check = |(position, column): (usize, usize), valid: bool| check_position(position, valid, size, line_no, column);
+
+    let query = match cmd {
+        "access" => {
+            let p = number(&mut tokens, "position")?;
+            check(p, p.0 < size)?;
+            Query::Access(p.0)
+        },
+        "rank" | "select" | "next" | "prev" | "select_from" => {
+            let one = bit(&mut tokens)?;
+            let (p, p_column) = number(&mut tokens, "position")?;
+            match cmd {
+                "rank" | "next" | "select_from" => check((p, p_column), p <= size)?,
+                "prev" => check((p, p_column), p < size)?,
+                _ => {},
+            }
+            match (cmd, one) {
+                ("rank", false) => Query::Rank0(p),
+                ("rank", true) => Query::Rank1(p),
+                ("select", false) => Query::Select0(p),
+                ("select", true) => Query::Select1(p),
+                ("next", false) => Query::Next0(p),
+                ("next", true) => Query::Next1(p),
+                ("prev", false) => Query::Prev0(p),
+                ("prev", true) => Query::Prev1(p),
+                (_, false) => Query::SelectFrom0(p, number(&mut tokens, "count")?.0),
+                (_, true) => Query::SelectFrom1(p, number(&mut tokens, "count")?.0),
+            }
+        },
+        "count" => {
+            let (l, l_column) = number(&mut tokens, "start position")?;
+            let (r, r_column) = number(&mut tokens, "end position")?;
+            check((r, r_column), r <= size)?;
+            if l > r {
+                return Err(ParseError::new(line_no, l_column, ParseErrorReason::InvalidRange { l, r }));
+            }
+            Query::Count(l, r)
+        },
+        _ => return Err(ParseError::new(line_no, column, ParseErrorReason::UnknownCommand(cmd.to_string()))),
+    };
+
+    if let Some((column, token)) = tokens.next() {
+        return Err(ParseError::new(line_no, column, ParseErrorReason::TrailingInput(token.to_string())));
+    }
+    Ok(Some(query))
+}
+
+fn check_position(position: usize, valid: bool, size: usize, line_no: usize, column: usize) -> Result<(), ParseError> {
+    if valid {
+        Ok(())
+    } else {
+        Err(ParseError::new(line_no, column, ParseErrorReason::PositionOutOfRange { position, size }))
+    }
+}
+
+fn parse_number(token: &str, line_no: usize, column: usize) -> Result<usize, ParseError> {
+    token.parse::<usize>().map_err(|_| ParseError::new(line_no, column, ParseErrorReason::InvalidNumber(token.to_string())))
+}
+
+// The whitespace separated tokens of a line, with their (1-based) column.
+fn tokens(line: &str) -> impl Iterator<Item = (usize, &str)> + '_ {
+    line.split_ascii_whitespace().map(move |token| (token.as_ptr() as usize - line.as_ptr() as usize + 1, token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bvec::RankSelectVector;
+
+    fn parse(text: &str) -> Result<Input, ParseError> {
+        parse_input(&mut text.as_bytes())
+    }
+
+    fn parse_err(text: &str) -> (usize, usize, ParseErrorReason) {
+        let e = parse(text).err().unwrap();
+        (e.line, e.column, e.reason)
+    }
+
+    #[test]
+    fn valid_input() {
+        let expected = vec![Query::Access(4), Query::Rank0(10), Query::Select1(14), Query::Rank1(10), Query::Select0(3)];
+        let text = "5\n0011101101010101111111111\naccess 4\nrank 0 10\nselect 1 14\nrank 1 10\nselect 0 3\n";
+        for text in [text.to_string(), text.replace('\n', "\r\n"), text.trim_end().to_string(), text.replace("0 10", "0  10") + "\n\n"] {
+            let input = parse(&text).unwrap();
+            assert_eq!(input.bits.size(), 25);
+            assert_eq!(input.bits.rank(25), 18);
+            assert_eq!(input.queries, expected, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn range_queries() {
+        let text = "8\n0011101101\ncount 2 10\ncount 3 3\nnext 0 10\nnext 1 2\nprev 0 9\nprev 1 0\nselect_from 1 10 1\nselect_from 0 3 2\n";
+        let input = parse(text).unwrap();
+        assert_eq!(input.queries, [Query::Count(2, 10), Query::Count(3, 3), Query::Next0(10), Query::Next1(2),
+            Query::Prev0(9), Query::Prev1(0), Query::SelectFrom1(10, 1), Query::SelectFrom0(3, 2)]);
+        let text_again = input.queries.iter().map(|q| format!("{}\n", q)).collect::<String>();
+        assert_eq!(text_again, text.split_once("0011101101\n").unwrap().1);
+    }
+
+    #[test]
+    fn bits_without_newline() {
+        // Only the bit vector, without a line ending: this used to loop forever.
+        for (text, size) in [("0\n0110", 4), ("0\n\n", 0), ("0\n1\r", 1), ("0\n\r\n", 0)] {
+            let input = parse(text).unwrap();
+            assert_eq!(input.bits.size(), size, "{:?}", text);
+            assert!(input.queries.is_empty());
+        }
+
+        let bits = "01".repeat(100);
+        let input = parse(&format!("0\n{}", bits)).unwrap();
+        assert_eq!(input.bits.size(), 200);
+        assert_eq!(input.bits.rank(200), 100);
+        assert_eq!(input.bits.access(199), 1);
+    }
+
+    #[test]
+    fn errors() {
+        use ParseErrorReason::*;
+        assert_eq!(parse_err(""), (1, 1, UnexpectedEof("the number of queries")));
+        assert_eq!(parse_err("\n0101\n"), (1, 1, MissingArgument("number of queries")));
+        assert_eq!(parse_err(" x1\n0101\n"), (1, 2, InvalidNumber("x1".into())));
+        assert_eq!(parse_err("1\n"), (2, 1, UnexpectedEof("the bit vector")));
+        assert_eq!(parse_err("1\n0120\n"), (2, 3, InvalidBit('2')));
+        assert_eq!(parse_err("1\n01 0\n"), (2, 3, InvalidBit(' ')));
+        assert_eq!(parse_err("1\n01\r0\n"), (2, 3, InvalidBit('\r')));
+        assert_eq!(parse_err("1\n0101\nacces 1\n"), (3, 1, UnknownCommand("acces".into())));
+        assert_eq!(parse_err("1\n0101\naccess\n"), (3, 7, MissingArgument("position")));
+        assert_eq!(parse_err("1\n0101\r\nrank 1\r\n"), (3, 7, MissingArgument("position")));
+        assert_eq!(parse_err("1\n0101\nselect\n"), (3, 7, MissingArgument("bit value")));
+        assert_eq!(parse_err("1\n0101\nselect 2 1\n"), (3, 8, InvalidBitValue("2".into())));
+        assert_eq!(parse_err("1\n0101\nrank 1 -1\n"), (3, 8, InvalidNumber("-1".into())));
+        assert_eq!(parse_err("2\n0101\naccess 1\naccess 1 2\n"), (4, 10, TrailingInput("2".into())));
+        assert_eq!(parse_err("1\n0101\naccess 4\n"), (3, 8, PositionOutOfRange { position: 4, size: 4 }));
+        assert_eq!(parse_err("1\n0101\nrank 0 5\n"), (3, 8, PositionOutOfRange { position: 5, size: 4 }));
+        assert_eq!(parse_err("1\n0101\ncount 1\n"), (3, 8, MissingArgument("end position")));
+        assert_eq!(parse_err("1\n0101\ncount 0 5\n"), (3, 9, PositionOutOfRange { position: 5, size: 4 }));
+        assert_eq!(parse_err("1\n0101\ncount 3 2\n"), (3, 7, InvalidRange { l: 3, r: 2 }));
+        assert_eq!(parse_err("1\n0101\nnext 1 5\n"), (3, 8, PositionOutOfRange { position: 5, size: 4 }));
+        assert_eq!(parse_err("1\n0101\nprev 0 4\n"), (3, 8, PositionOutOfRange { position: 4, size: 4 }));
+        assert_eq!(parse_err("1\n0101\nselect_from 1 5 999999\n"), (3, 15, PositionOutOfRange { position: 5, size: 4 }));
+        assert_eq!(parse_err("1\n0101\nprev x 1\n"), (3, 6, InvalidBitValue("x".into())));
+        assert_eq!(parse_err("1\n0101\nselect_from 1 2\n"), (3, 16, MissingArgument("count")));
+        assert_eq!(parse_err("1\n0101\nselect_from 1 2 3 4\n"), (3, 19, TrailingInput("4".into())));
+    }
+
+    #[test]
+    fn pack_bits_in_pieces() {
+        let str = crate::tst::generate_random_bits_string(1000, 21, 0.5);
+        let expected = BitVector::new_from_string(&str);
+
+        // Pieces which start and end inside words, like chunks of a long line.
+        for piece in [1, 7, 63, 64, 65, 130, 1000] {
+            let mut v = vec![];
+            for (k, chunk) in str.as_bytes().chunks(piece).enumerate() {
+                pack_bits(&mut v, k * piece, chunk, 2).unwrap();
+            }
+            let bv = BitVector::from_words(v, str.len());
+            assert!((0..str.len()).all(|i| bv.get_nth(i) == expected.get_nth(i)), "piece={}", piece);
+        }
+
+        // The column of an invalid character in the head, in a whole word and in the tail.
+        for (start, column) in [(10, 15), (10, 64), (10, 100), (10, 205), (0, 1), (0, 64), (0, 65), (0, 200)] {
+            let mut chars = str.as_bytes()[..200].to_vec();
+            chars[column - start - 1] = b'a';
+            let e = pack_bits(&mut vec![0], start, &chars, 2).err().unwrap();
+            assert_eq!((e.line, e.column, e.reason), (2, column, ParseErrorReason::InvalidBit('a')));
+        }
+    }
+
+    const FORMATS: [BitsFormat; 4] = [BitsFormat::Ascii, BitsFormat::Hex, BitsFormat::Packed, BitsFormat::Positions];
+
+    fn write_to_vec(bits: &BitVector, format: BitsFormat) -> Vec<u8> {
+        let mut out = vec![];
+        format.write(bits, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn formats_roundtrip() {
+        for (size, weight0) in [(0, 0.5), (1, 0.0), (3, 0.5), (63, 0.5), (64, 0.0), (65, 0.5), (1000, 0.5), (5000, 0.99), (70000, 0.5)] {
+            let str = crate::tst::generate_random_bits_string(size, size as u64, weight0);
+            let bits = BitVector::new_from_string(&str);
+
+            for format in FORMATS {
+                let text = write_to_vec(&bits, format);
+                let read = format.read(&mut text.as_slice(), 2).unwrap();
+                assert_eq!(read.size(), size, "{:?}", format);
+                assert_eq!(write_to_vec(&read, BitsFormat::Ascii), format!("{}\n", str).into_bytes(), "{:?} size={}", format, size);
+
+                // Converting to every other format gives the same bits.
+                for other in FORMATS {
+                    assert_eq!(write_to_vec(&read, other), write_to_vec(&bits, other), "{:?} -> {:?}", format, other);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn formats_written() {
+        let bits = BitVector::new_from_string("100000001");
+        assert_eq!(write_to_vec(&bits, BitsFormat::Hex), b"9 101\n");
+        assert_eq!(write_to_vec(&bits, BitsFormat::Positions), b"9 0 8\n");
+        assert_eq!(write_to_vec(&bits, BitsFormat::Packed), [9, 0, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(BitVector::new_from_hex(&mut "9 1F1".as_bytes()).unwrap().size(), 9);
+        assert_eq!(BitVector::new_from_positions(&mut "5\r\n".as_bytes()).unwrap().size(), 5);
+        // \r\n split between two fills of the buffer.
+        for capacity in 1..8 {
+            let mut reader = std::io::BufReader::with_capacity(capacity, "9 1F1\r\n4 0\r\r\n".as_bytes());
+            assert_eq!(BitVector::new_from_hex(&mut reader).unwrap().iter_ones().collect::<Vec<_>>(), [0, 4, 5, 6, 7, 8]);
+            assert_eq!(BitVector::new_from_hex(&mut reader).err().unwrap().reason, ParseErrorReason::InvalidHexDigit('\r'));
+        }
+        assert_eq!(BitVector::new_from_positions(&mut "5  1\t3 ".as_bytes()).unwrap().iter_ones().collect::<Vec<_>>(), [1, 3]);
+
+        // Bits after the end in the last hex digit or packed word are ignored.
+        assert_eq!(BitVector::new_from_hex(&mut "2 f".as_bytes()).unwrap().rank(2), 2);
+        assert_eq!(BitVector::new_from_packed(&mut [2u8, 0, 0, 0, 0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF].as_slice()).unwrap().words(), [3]);
+    }
+
+    #[test]
+    fn input_in_formats() {
+        let text = "3\n0011101101\naccess 4\nrank 0 10\nselect 1 4\n";
+        let input = parse(text).unwrap();
+        for format in FORMATS {
+            let mut converted = vec![];
+            write_input(&mut converted, &input, format).unwrap();
+            let again = parse_input_as(&mut converted.as_slice(), format).unwrap();
+            assert_eq!(again.queries, input.queries, "{:?}", format);
+            assert_eq!(write_to_vec(&again.bits, BitsFormat::Ascii), b"0011101101\n", "{:?}", format);
+
+            let mut back = vec![];
+            write_input(&mut back, &again, BitsFormat::Ascii).unwrap();
+            assert_eq!(back, text.as_bytes());
+        }
+
+        // The line ending after the packed words is optional.
+        let mut packed = b"1\n".to_vec();
+        input.bits.write_packed(&mut packed).unwrap();
+        packed.extend(b"access 3");
+        assert_eq!(parse_input_as(&mut packed.as_slice(), BitsFormat::Packed).unwrap().queries, [Query::Access(3)]);
+    }
+
+    #[test]
+    fn format_errors() {
+        use ParseErrorReason::*;
+        let err = |format: BitsFormat, text: &[u8]| {
+            let e = format.read(&mut &text[..], 2).err().unwrap();
+            (e.column, e.reason)
+        };
+
+        assert_eq!(err(BitsFormat::Hex, b""), (1, UnexpectedEof("the bit vector")));
+        assert_eq!(err(BitsFormat::Hex, b"\n"), (1, MissingArgument("number of bits")));
+        assert_eq!(err(BitsFormat::Hex, b"x 0\n"), (1, InvalidNumber("x".into())));
+        assert_eq!(err(BitsFormat::Hex, b"8 0g\n"), (4, InvalidHexDigit('g')));
+        assert_eq!(err(BitsFormat::Hex, b"8 0 \n"), (4, InvalidHexDigit(' ')));
+        assert_eq!(err(BitsFormat::Hex, b"9 00\n"), (5, WrongHexLength { size: 9, digits: 2 }));
+        assert_eq!(err(BitsFormat::Hex, b"4 00\r\n"), (5, WrongHexLength { size: 4, digits: 2 }));
+        assert_eq!(err(BitsFormat::Hex, b"4"), (2, WrongHexLength { size: 4, digits: 0 }));
+
+        assert_eq!(err(BitsFormat::Positions, b"10 1 x"), (6, InvalidNumber("x".into())));
+        assert_eq!(err(BitsFormat::Positions, b"10 1 10\n"), (6, PositionOutOfRange { position: 10, size: 10 }));
+        assert_eq!(err(BitsFormat::Positions, b"10 5 5\n"), (6, UnsortedPosition { position: 5, previous: 5 }));
+        assert_eq!(err(BitsFormat::Positions, b"10 5 3\n"), (6, UnsortedPosition { position: 3, previous: 5 }));
+        assert_eq!(err(BitsFormat::Positions, b"10 1\r2\n"), (4, InvalidNumber("1\r2".into())));
+        assert_eq!(err(BitsFormat::Positions, b" 18446744073709551615 5\n"), (2, SizeTooLarge(usize::MAX)));
+        assert_eq!(err(BitsFormat::Positions, b"18446744073709551615"), (1, SizeTooLarge(usize::MAX)));
+
+        assert_eq!(err(BitsFormat::Packed, b"\x01\0\0"), (1, UnexpectedEof("the number of bits")));
+        assert_eq!(err(BitsFormat::Packed, b"\x41\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0"), (1, UnexpectedEof("more packed words")));
+    }
+
+    #[test]
+    fn error_message() {
+        let e = parse("1\n0101\naccess x\n").err().unwrap();
+        assert_eq!(e.to_string(), "3:8: invalid number \"x\"");
+    }
+}
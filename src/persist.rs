@@ -0,0 +1,419 @@
+// Binary file format for a built RASBVec (FastRASBVec or RuntimeRASBVec), so that the rank/select
+// directory does not have to be rebuilt on every start.
+//
+// The file can either be read into memory (`read_from`), or memory-mapped and used in place
+// (`from_mmap`). For the latter, every array in the file is aligned to 8 bytes relative to the start
+// of the file (and thus to the page-aligned start of the mapping).
+//
+// The select samples (if the layout has a select sample rate) and the Eytzinger megablocks are not
+// part of the file, they are recomputed from the RankSuperblocks and megablocks when loading. The
+// select sample rate and whether there are Eytzinger megablocks are stored, so a RankSelectConfig read
+// from a file has the same search structures.
+//
+// The RankSuperblocks are read with 64-bit loads which may extend up to 8 bytes past the end of the
+// RankSuperblock data (see RANK_PADDING in fast_bvec.rs). In a mapped file these bytes are the zero
+// padding and the number of megablocks, which always follow the data.
+//
+// All integers are stored in the native byte order of the machine which wrote the file, the
+// endianness marker is used to detect files written on a machine with a different byte order.
+//
+//   offset  size  field
+//   0       8     magic, "RASBVEC\0"
+//   8       4     format version (u32, currently 1)
+//   12      4     endianness marker (u32, 0x01020304 in native byte order)
+//   16      8     block size (u64)
+//   24      8     superblock size (u64)
+//   32      8     megablock factor (u64)
+//   40      8     superblock bits (u64)
+//   48      8     select sample rate (u64, 0 without select samples)
+//   56      8     flags (u64, bit 0: Eytzinger megablocks, the other bits are 0)
+//   64      8     number of bits in the bit vector (u64)
+//   72      8     number of set bits in the bit vector (u64)
+//   80      8     W: number of BitCell words (u64)
+//   88      8*W   the BitCell words of the bit vector
+//   ...     8     S: number of bytes of RankSuperblock data (u64)
+//   ...     S     the packed RankSuperblocks, followed by zero padding up to a multiple of 8 bytes
+//   ...     8     M: number of megablocks (u64)
+//   ...     8*M   the megablocks (u64 each)
+
+use crate::bvec::{BitCell, BitVector, BIT_CELL_SIZE};
+use crate::fast_bvec::{CacheBlock, RankSupport, RASBVec, RASBVecLayout, RANK_PADDING};
+use crate::storage::Storage;
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+pub const MAGIC: [u8; 8] = *b"RASBVEC\0";
+pub const VERSION: u32 = 1;
+const ENDIANNESS_MARKER: u32 = 0x01020304;
+const FLAG_EYTZINGER_MEGABLOCKS: u64 = 1;
+
+// Number of words which are converted to bytes at once when reading or writing.
+const WORDS_PER_CHUNK: usize = 1 << 13;
+
+fn invalid_data(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+fn write_u64(w: &mut impl Write, value: u64) -> io::Result<()> {
+    w.write_all(&value.to_ne_bytes())
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_ne_bytes(buf))
+}
+
+fn read_usize(r: &mut impl Read) -> io::Result<usize> {
+    let value = read_u64(r)?;
+    usize::try_from(value).map_err(|_| invalid_data(format!("value {} does not fit in usize", value)))
+}
+
+fn write_words(w: &mut impl Write, words: &[u64]) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(WORDS_PER_CHUNK * 8);
+    for chunk in words.chunks(WORDS_PER_CHUNK) {
+        buf.clear();
+        chunk.iter().for_each(|word| buf.extend_from_slice(&word.to_ne_bytes()));
+        w.write_all(&buf)?;
+    }
+    Ok(())
+}
+
+// The Vec grows with the words which were actually read, so a corrupt length in the file fails with
+// UnexpectedEof instead of a huge allocation.
+fn read_words(r: &mut impl Read, n: usize) -> io::Result<Vec<u64>> {
+    let mut words = Vec::new();
+    let mut buf = vec![0u8; WORDS_PER_CHUNK * 8];
+    while words.len() < n {
+        let count = std::cmp::min(WORDS_PER_CHUNK, n - words.len());
+        r.read_exact(&mut buf[..count * 8])?;
+        words.extend(buf[..count * 8].chunks_exact(8).map(|b| u64::from_ne_bytes(b.try_into().unwrap())));
+    }
+    Ok(words)
+}
+
+// Number of zero bytes needed after `len` bytes to reach a multiple of 8.
+pub(crate) fn padding(len: usize) -> usize {
+    len.next_multiple_of(8) - len
+}
+
+impl<Layout: RASBVecLayout> RASBVec<Layout> {
+    // Write the bit vector together with its rank/select directory in the format described above.
+    // Many small writes are issued, so `w` should be buffered.
+    pub fn write_to(&self, w: &mut impl Write) -> io::Result<()> {
+        let layout = self.layout();
+
+        w.write_all(&MAGIC)?;
+        w.write_all(&VERSION.to_ne_bytes())?;
+        w.write_all(&ENDIANNESS_MARKER.to_ne_bytes())?;
+        write_u64(w, layout.block_size() as u64)?;
+        write_u64(w, layout.superblock_size() as u64)?;
+        write_u64(w, layout.megablock_factor() as u64)?;
+        write_u64(w, layout.superblock_bits() as u64)?;
+        write_u64(w, layout.select_sample_rate() as u64)?;
+        write_u64(w, if layout.eytzinger_megablocks() { FLAG_EYTZINGER_MEGABLOCKS } else { 0 })?;
+        write_u64(w, self.bits.size() as u64)?;
+        write_u64(w, self.count1 as u64)?;
+
+        let words = self.bits.words();
+        write_u64(w, words.len() as u64)?;
+        write_words(w, words)?;
+
+        let packed = self.rank.packed();
+        write_u64(w, packed.len() as u64)?;
+        w.write_all(packed)?;
+        w.write_all(&[0; 8][..padding(packed.len())])?;
+
+        write_u64(w, self.megablocks.len() as u64)?;
+        write_words(w, &self.megablocks.iter().map(|x| *x as u64).collect::<Vec<_>>())?;
+        Ok(())
+    }
+
+    // Load a structure written with `write_to`. Fails with ErrorKind::InvalidData if the file is not
+    // in the expected format, if its counts do not agree with each other, or if its parameters do not
+    // match the layout of this type.
+    pub fn read_from(r: &mut impl Read) -> io::Result<Self> {
+        let header = Header::<Layout>::read(r)?;
+
+        let n_words = header.check_words(read_usize(r)?)?;
+        let words: Vec<BitCell> = read_words(r, n_words)?;
+
+        let rank_len = header.check_rank_len(read_usize(r)?)?;
+        // Like read_words, only allocate for the bytes which are in the file.
+        let mut data: Vec<CacheBlock> = vec![];
+        (&mut *r).take(rank_len as u64).read_to_end(&mut data)?;
+        if data.len() < rank_len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file ends in the superblock data"));
+        }
+        data.resize(rank_len + RANK_PADDING, 0);
+        r.read_exact(&mut [0; 8][..padding(rank_len)])?;
+
+        let n_mega = header.check_megablocks(read_usize(r)?)?;
+        let megablocks = read_words(r, n_mega)?.into_iter().map(|x| x as usize).collect();
+        header.build(words.into(), data.into(), megablocks)
+    }
+
+    // Use a memory-mapped file written with `write_to` without copying it: the BitCells and the
+    // RankSuperblocks are used directly from the mapping, only the (small) megablock array is copied.
+    // The checks are the same as in `read_from`.
+    pub fn from_mmap(map: Arc<Mmap>) -> io::Result<Self> {
+        let mut r = io::Cursor::new(&map[..]);
+        let header = Header::<Layout>::read(&mut r)?;
+
+        let n_words = header.check_words(read_usize(&mut r)?)?;
+        let words = Storage::mapped(map.clone(), r.position() as usize, n_words)?;
+        r.seek(SeekFrom::Current((n_words * 8) as i64))?;
+
+        let rank_len = header.check_rank_len(read_usize(&mut r)?)?;
+        // The RANK_PADDING bytes after the RankSuperblocks are always in the file: the number of
+        // megablocks follows them.
+        let data = Storage::mapped(map.clone(), r.position() as usize, rank_len + RANK_PADDING)?;
+        r.seek(SeekFrom::Current((rank_len + padding(rank_len)) as i64))?;
+
+        let n_mega = header.check_megablocks(read_usize(&mut r)?)?;
+        let megablocks = read_words(&mut r, n_mega)?.into_iter().map(|x| x as usize).collect();
+        header.build(words, data, megablocks)
+    }
+
+    /// Map the file at `path` and use it with `from_mmap`.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified (by this or any other process) while the returned structure is
+    /// alive, see `memmap2::Mmap::map`.
+    pub unsafe fn open_mapped(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::open(path)?;
+        Self::from_mmap(Arc::new(Mmap::map(&file)?))
+    }
+}
+
+// The fixed-size part at the start of the file, together with the checks for the sizes of the
+// following sections.
+struct Header<Layout: RASBVecLayout> {
+    layout: Layout,
+    size: usize,
+    count1: usize,
+}
+
+impl<Layout: RASBVecLayout> Header<Layout> {
+    fn read(r: &mut impl Read) -> io::Result<Self> {
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(invalid_data("not a rank/select bit vector file (wrong magic number)".to_string()));
+        }
+
+        let mut buf = [0u8; 4];
+        r.read_exact(&mut buf)?;
+        let version = u32::from_ne_bytes(buf);
+        r.read_exact(&mut buf)?;
+        if u32::from_ne_bytes(buf) != ENDIANNESS_MARKER {
+            return Err(invalid_data("file was written on a machine with a different byte order".to_string()));
+        }
+
+        if version != VERSION {
+            return Err(invalid_data(format!("unsupported format version {} (expected {})", version, VERSION)));
+        }
+
+        let block_size = read_usize(r)?;
+        let superblock_size = read_usize(r)?;
+        let megablock_factor = read_usize(r)?;
+        let superblock_bits = read_usize(r)?;
+        let select_sample_rate = read_usize(r)?;
+        let flags = read_u64(r)?;
+        if flags & !FLAG_EYTZINGER_MEGABLOCKS != 0 {
+            return Err(invalid_data(format!("unknown flags {:#x}", flags)));
+        }
+        let layout = Layout::from_values(block_size, superblock_size, megablock_factor, superblock_bits,
+                                         select_sample_rate, flags & FLAG_EYTZINGER_MEGABLOCKS != 0)
+            .ok_or_else(|| invalid_data(format!(
+                "parameters in file (block_size={}, superblock_size={}, megablock_factor={}, superblock_bits={}) do not match the layout",
+                block_size, superblock_size, megablock_factor, superblock_bits)))?;
+
+        let size = read_usize(r)?;
+        let count1 = read_usize(r)?;
+        if count1 > size {
+            return Err(invalid_data(format!("{} set bits in a bit vector of {} bits", count1, size)));
+        }
+
+        Ok(Header { layout, size, count1 })
+    }
+
+    fn n_superblocks(&self) -> usize {
+        self.size.div_ceil(self.layout.superblock_size())
+    }
+
+    fn check_words(&self, n_words: usize) -> io::Result<usize> {
+        if n_words != self.size.div_ceil(BIT_CELL_SIZE) {
+            return Err(invalid_data(format!("{} words for a bit vector of {} bits", n_words, self.size)));
+        }
+        Ok(n_words)
+    }
+
+    fn check_rank_len(&self, rank_len: usize) -> io::Result<usize> {
+        let expected = self.n_superblocks().checked_mul(self.layout.cacheline_size())
+            .ok_or_else(|| invalid_data(format!("too many superblocks for a bit vector of {} bits", self.size)))?;
+        if rank_len != expected {
+            return Err(invalid_data(format!("{} bytes of superblock data, expected {}", rank_len, expected)));
+        }
+        Ok(rank_len)
+    }
+
+    fn check_megablocks(&self, n_mega: usize) -> io::Result<usize> {
+        let expected = self.n_superblocks().div_ceil(self.layout.megablock_factor());
+        if n_mega != expected {
+            return Err(invalid_data(format!("{} megablocks, expected {}", n_mega, expected)));
+        }
+        Ok(n_mega)
+    }
+
+    // The sizes were checked while reading, the counts are checked here before select relies on them.
+    fn build(self, words: Storage<BitCell>, data: Storage<CacheBlock>, megablocks: Vec<usize>) -> io::Result<RASBVec<Layout>> {
+        let mut vec = RASBVec {
+            bits: BitVector::from_words(words, self.size),
+            rank: RankSupport {
+                data,
+                layout: self.layout,
+            },
+            megablocks,
+            select_samples: [vec![], vec![]],
+            eytzinger_megablocks: None,
+            count0: self.size - self.count1,
+            count1: self.count1,
+        };
+        vec.check_counts().map_err(invalid_data)?;
+        vec.init_select_samples();
+        vec.init_eytzinger_megablocks();
+        Ok(vec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bvec::RankSelectVector;
+    use crate::config::RankSelectConfig;
+    use crate::fast_bvec::{BigRASB, FastRASBVec, RASBVecParameters, RuntimeRASBVec, SmallRASB};
+    use crate::query::ExecQueries;
+    use crate::tst::*;
+
+    fn roundtrip<Layout: RASBVecLayout>(v: &RASBVec<Layout>) -> Vec<u8> {
+        let mut file = vec![];
+        v.write_to(&mut file).unwrap();
+        assert_eq!(file.len() % 8, 0);
+
+        let loaded = RASBVec::<Layout>::read_from(&mut file.as_slice()).unwrap();
+        assert_eq!(loaded.bits.words(), v.bits.words());
+        assert_eq!(loaded.rank.packed(), v.rank.packed());
+        assert_eq!(loaded.megablocks, v.megablocks);
+        assert_eq!(loaded.select_samples, v.select_samples);
+
+        let queries = generate_random_queries(1000, 5, v.size(), Some(v.count1));
+        let expected = queries.iter().exec_queries(v).collect::<Vec<_>>();
+        check_answers(&loaded, &queries, &expected);
+        file
+    }
+
+    #[test]
+    fn write_and_read() {
+        let n = BigRASB::SUPERBLOCK_SIZE * 70 + 13;
+        let bits = BitVector::generate_random(n, 7);
+        roundtrip(&FastRASBVec::<BigRASB>::new(bits.clone()));
+        // Select samples and Eytzinger megablocks are not stored, they are recomputed when loading.
+        roundtrip(&FastRASBVec::<crate::fast_bvec::Params<256, 1024, 32, 40, 500>>::new(bits.clone()));
+        let eytzinger_file = roundtrip(&FastRASBVec::<crate::fast_bvec::Params<256, 1024, 2, 40, 0, true>>::new(bits.clone()));
+        assert!(FastRASBVec::<crate::fast_bvec::Params<256, 1024, 2, 40, 0, true>>::read_from(&mut eytzinger_file.as_slice()).unwrap()
+            .eytzinger_megablocks.is_some());
+
+        let config = RankSelectConfig::new(64, 512, 4, 33).unwrap();
+        let file = roundtrip(&RuntimeRASBVec::with_config(bits.clone(), config).unwrap());
+
+        // The select sample rate of a runtime config is part of the file.
+        let sampled = config.with_select_sample_rate(300);
+        let sampled_file = roundtrip(&RuntimeRASBVec::with_config(bits.clone(), sampled).unwrap());
+        let loaded = RuntimeRASBVec::read_from(&mut sampled_file.as_slice()).unwrap();
+        assert_eq!(*loaded.layout(), sampled);
+        assert!(!loaded.select_samples[1].is_empty());
+
+        // So is the Eytzinger flag, the Eytzinger megablocks are rebuilt when loading.
+        let eytzinger = config.with_eytzinger_megablocks(true);
+        let eytzinger_file = roundtrip(&RuntimeRASBVec::with_config(bits, eytzinger).unwrap());
+        let loaded = RuntimeRASBVec::read_from(&mut eytzinger_file.as_slice()).unwrap();
+        assert_eq!(*loaded.layout(), eytzinger);
+        assert!(loaded.eytzinger_megablocks.is_some());
+
+        // The layout of a runtime config is taken from the file, but it must match for a fixed one.
+        let err = FastRASBVec::<BigRASB>::read_from(&mut file.as_slice()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn invalid_files() {
+        let v = FastRASBVec::<SmallRASB>::new(BitVector::new_from_string("0011101011"));
+        let mut file = vec![];
+        v.write_to(&mut file).unwrap();
+
+        let mut wrong_magic = file.clone();
+        wrong_magic[0] = b'X';
+        let err = FastRASBVec::<SmallRASB>::read_from(&mut wrong_magic.as_slice()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut wrong_version = file.clone();
+        wrong_version[8..12].copy_from_slice(&(VERSION + 1).to_ne_bytes());
+        let err = FastRASBVec::<SmallRASB>::read_from(&mut wrong_version.as_slice()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let truncated = &file[..file.len() - 3];
+        let err = FastRASBVec::<SmallRASB>::read_from(&mut &truncated[..]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        // Huge lengths which are consistent with each other, but not with the length of the file.
+        let size = usize::MAX / 4;
+        let mut huge = file.clone();
+        huge[64..72].copy_from_slice(&(size as u64).to_ne_bytes());
+        huge[80..88].copy_from_slice(&(size.div_ceil(BIT_CELL_SIZE) as u64).to_ne_bytes());
+        let err = FastRASBVec::<SmallRASB>::read_from(&mut huge.as_slice()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        // A number of set bits which does not match the superblocks and the words.
+        let mut wrong_count = file.clone();
+        wrong_count[72..80].copy_from_slice(&(v.count1 as u64 - 1).to_ne_bytes());
+        let err = FastRASBVec::<SmallRASB>::read_from(&mut wrong_count.as_slice()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // A megablock which is not the value of its first superblock.
+        let mut wrong_megablock = file.clone();
+        let last = wrong_megablock.len() - 8;
+        wrong_megablock[last..].copy_from_slice(&1u64.to_ne_bytes());
+        let err = FastRASBVec::<SmallRASB>::read_from(&mut wrong_megablock.as_slice()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn mapped() {
+        let n = BigRASB::SUPERBLOCK_SIZE * 33 + 1001;
+        let v = FastRASBVec::<BigRASB>::new(BitVector::generate_random(n, 9));
+        let path = std::env::temp_dir().join(format!("bitvec-mapped-test-{}.bin", std::process::id()));
+        v.write_to(&mut io::BufWriter::new(File::create(&path).unwrap())).unwrap();
+
+        let mapped = unsafe { FastRASBVec::<BigRASB>::open_mapped(&path).unwrap() };
+        std::fs::remove_file(&path).unwrap();
+        assert!(mapped.bits.is_mapped() && mapped.rank.data.is_mapped());
+
+        let queries = generate_random_queries(2000, 3, n, Some(v.count1));
+        let expected = queries.iter().exec_queries(&v).collect::<Vec<_>>();
+        check_answers(&mapped, &queries, &expected);
+
+        // Appending copies the bits out of the mapping. The RankSuperblocks are only copied once they
+        // change.
+        let (mut mapped, mut v) = (mapped, v);
+        mapped.extend([1, 0, 1]);
+        v.extend([1, 0, 1]);
+        assert!(!mapped.bits.is_mapped() && mapped.rank.data.is_mapped());
+        assert_eq!(mapped.rank(n + 3), v.rank(n + 3));
+        assert_eq!(mapped.select1(v.count1), Some(n + 2));
+    }
+}
@@ -0,0 +1,199 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+// Number of set bits in a slice of words.
+//
+// There are three implementations:
+//
+//   scalar  u64::count_ones on every word. Without `-C target-cpu` this is compiled to a broadword
+//           popcount, because the baseline x86_64 target does not have the popcnt instruction.
+//   popcnt  the same loop, compiled with the popcnt instruction.
+//   avx2    the Harley-Seal method from Muła, Kurz, Lemire ("Faster Population Counts Using AVX2
+//           Instructions"): 16 vectors of 256 bits are combined with a tree of carry-save adders, so
+//           that only one vector per 16 needs to be popcounted (with a nibble lookup table in
+//           vpshufb). The remaining words are counted with popcnt.
+//
+// The implementation is chosen at runtime on the first call, with CPU feature detection. Slices
+// shorter than one Harley-Seal iteration always use popcnt (if available), for them the setup of the
+// vectors is not worth it.
+
+const HARLEY_SEAL_WORDS: usize = 16 * 4;
+
+#[inline]
+pub(crate) fn count_ones_words(words: &[u64]) -> usize {
+    #[cfg(target_arch = "x86_64")]
+    match popcount_impl() {
+        // Safety: popcount_impl() only returns AVX2 or POPCNT if the CPU supports the instructions.
+        AVX2 if words.len() >= HARLEY_SEAL_WORDS => return unsafe { count_ones_avx2(words) },
+        AVX2 | POPCNT => return unsafe { count_ones_popcnt(words) },
+        _ => {}
+    }
+
+    count_ones_scalar(words)
+}
+
+pub(crate) fn count_ones_scalar(words: &[u64]) -> usize {
+    words.iter().map(|w| w.count_ones() as usize).sum()
+}
+
+/// # Safety
+///
+/// The CPU must support popcnt.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "popcnt")]
+pub(crate) unsafe fn count_ones_popcnt(words: &[u64]) -> usize {
+    words.iter().map(|w| w.count_ones() as usize).sum()
+}
+
+/// # Safety
+///
+/// The CPU must support AVX2 and popcnt.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2,popcnt")]
+pub(crate) unsafe fn count_ones_avx2(words: &[u64]) -> usize {
+    use core::arch::x86_64::*;
+
+    // Number of set bits in every 64-bit lane of v.
+    #[target_feature(enable = "avx2")]
+    fn popcount256(v: __m256i) -> __m256i {
+        let lookup = _mm256_setr_epi8(
+            0, 1, 1, 2, 1, 2, 2, 3, 1, 2, 2, 3, 2, 3, 3, 4,
+            0, 1, 1, 2, 1, 2, 2, 3, 1, 2, 2, 3, 2, 3, 3, 4,
+        );
+        let low_mask = _mm256_set1_epi8(0x0F);
+        let lo = _mm256_and_si256(v, low_mask);
+        let hi = _mm256_and_si256(_mm256_srli_epi16::<4>(v), low_mask);
+        let bytes = _mm256_add_epi8(_mm256_shuffle_epi8(lookup, lo), _mm256_shuffle_epi8(lookup, hi));
+        _mm256_sad_epu8(bytes, _mm256_setzero_si256())
+    }
+
+    // Carry-save adder: returns (high, low), the carry and sum bits of a + b + c.
+    #[target_feature(enable = "avx2")]
+    fn csa(a: __m256i, b: __m256i, c: __m256i) -> (__m256i, __m256i) {
+        let u = _mm256_xor_si256(a, b);
+        (_mm256_or_si256(_mm256_and_si256(a, b), _mm256_and_si256(u, c)), _mm256_xor_si256(u, c))
+    }
+
+    let nr_vectors = words.len() / 4;
+    let ptr = words.as_ptr() as *const __m256i;
+    let load = |i: usize| _mm256_loadu_si256(ptr.add(i));
+
+    let mut total = _mm256_setzero_si256();
+    let (mut ones, mut twos, mut fours, mut eights) =
+        (_mm256_setzero_si256(), _mm256_setzero_si256(), _mm256_setzero_si256(), _mm256_setzero_si256());
+
+    let mut i = 0;
+    while i + 16 <= nr_vectors {
+        let (twos_a, o) = csa(ones, load(i), load(i + 1));
+        let (twos_b, o) = csa(o, load(i + 2), load(i + 3));
+        let (fours_a, t) = csa(twos, twos_a, twos_b);
+        let (twos_a, o) = csa(o, load(i + 4), load(i + 5));
+        let (twos_b, o) = csa(o, load(i + 6), load(i + 7));
+        let (fours_b, t) = csa(t, twos_a, twos_b);
+        let (eights_a, f) = csa(fours, fours_a, fours_b);
+        let (twos_a, o) = csa(o, load(i + 8), load(i + 9));
+        let (twos_b, o) = csa(o, load(i + 10), load(i + 11));
+        let (fours_a, t) = csa(t, twos_a, twos_b);
+        let (twos_a, o) = csa(o, load(i + 12), load(i + 13));
+        let (twos_b, o) = csa(o, load(i + 14), load(i + 15));
+        let (fours_b, t) = csa(t, twos_a, twos_b);
+        let (eights_b, f) = csa(f, fours_a, fours_b);
+        let (sixteens, e) = csa(eights, eights_a, eights_b);
+
+        total = _mm256_add_epi64(total, popcount256(sixteens));
+        (ones, twos, fours, eights) = (o, t, f, e);
+        i += 16;
+    }
+
+    total = _mm256_slli_epi64::<4>(total);
+    total = _mm256_add_epi64(total, _mm256_slli_epi64::<3>(popcount256(eights)));
+    total = _mm256_add_epi64(total, _mm256_slli_epi64::<2>(popcount256(fours)));
+    total = _mm256_add_epi64(total, _mm256_slli_epi64::<1>(popcount256(twos)));
+    total = _mm256_add_epi64(total, popcount256(ones));
+    while i < nr_vectors {
+        total = _mm256_add_epi64(total, popcount256(load(i)));
+        i += 1;
+    }
+
+    let mut lanes = [0u64; 4];
+    _mm256_storeu_si256(lanes.as_mut_ptr() as *mut __m256i, total);
+    lanes.iter().sum::<u64>() as usize + count_ones_popcnt(&words[nr_vectors * 4..])
+}
+
+const UNKNOWN: u8 = 0;
+const AVX2: u8 = 1;
+const POPCNT: u8 = 2;
+const SCALAR: u8 = 3;
+
+static POPCOUNT_IMPL: AtomicU8 = AtomicU8::new(UNKNOWN);
+
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
+fn popcount_impl() -> u8 {
+    match POPCOUNT_IMPL.load(Ordering::Relaxed) {
+        UNKNOWN => {
+            let popcnt = std::is_x86_feature_detected!("popcnt");
+            let detected = if popcnt && std::is_x86_feature_detected!("avx2") {
+                AVX2
+            } else if popcnt {
+                POPCNT
+            } else {
+                SCALAR
+            };
+            POPCOUNT_IMPL.store(detected, Ordering::Relaxed);
+            detected
+        }
+        detected => detected,
+    }
+}
+
+// Name of the implementation used by count_ones_words for long slices, for the benchmarks.
+pub fn count_ones_impl() -> &'static str {
+    cfg_if::cfg_if! {
+        if #[cfg(target_arch = "x86_64")] {
+            match popcount_impl() {
+                AVX2 => "avx2",
+                POPCNT => "popcnt",
+                _ => "scalar",
+            }
+        } else {
+            "scalar"
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand_xoshiro::Xoshiro256Plus;
+
+    #[test]
+    fn count_ones_all_paths() {
+        let mut rng = Xoshiro256Plus::seed_from_u64(15);
+        let words = (0..1000).map(|i| match i % 3 {
+            0 => rng.gen::<u64>(),
+            1 => u64::MAX,
+            _ => rng.gen::<u64>() & rng.gen::<u64>(),
+        }).collect::<Vec<_>>();
+
+        // Unaligned starts and lengths around multiples of a Harley-Seal iteration.
+        for start in [0, 1, 3, 7] {
+            for len in (0..200).chain([255, 256, 257, 511, 512, 513, 900, 993]) {
+                let slice = &words[start..start + len];
+                let expected = slice.iter().map(|w| (0..64).filter(|b| (w >> b) & 1 == 1).count()).sum::<usize>();
+                assert_eq!(count_ones_scalar(slice), expected, "scalar, start={} len={}", start, len);
+                assert_eq!(count_ones_words(slice), expected, "dispatch, start={} len={}", start, len);
+
+                #[cfg(target_arch = "x86_64")]
+                {
+                    if std::is_x86_feature_detected!("popcnt") {
+                        assert_eq!(unsafe { count_ones_popcnt(slice) }, expected, "popcnt, start={} len={}", start, len);
+                    }
+                    if std::is_x86_feature_detected!("avx2") && std::is_x86_feature_detected!("popcnt") {
+                        assert_eq!(unsafe { count_ones_avx2(slice) }, expected, "avx2, start={} len={}", start, len);
+                    }
+                }
+            }
+        }
+    }
+}
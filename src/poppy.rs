@@ -0,0 +1,236 @@
+use crate::bvec::{BitVector, RankSelectVector};
+
+// Poppy (Zhou, Andersen, Kaminsky, "Space-Efficient, High-Performance Rank & Select Structures on
+// Uncompressed Bit Sequences") with combined sampling for select (cs-poppy).
+//
+// The index has three levels:
+//
+//   L0  one 64-bit counter for every 2^L0_BITS bits (2^32 by default): the number of set bits
+//       before the upper block.
+//   L1  one 64-bit entry for every basic block of 2048 bits. The low 32 bits are the number of set
+//       bits before the basic block, relative to its upper block. They are followed by three 10-bit
+//       L2 counters, the number of set bits in the first three 512-bit sub-blocks of the basic
+//       block (the fourth one follows from the next L1 entry and is not needed).
+//
+// The L1 and L2 counters are interleaved in one word, so rank reads one L0 counter (which is almost
+// always cached, there are very few of them), one L1 entry and popcounts at most 8 words.
+//
+// For select, the position of every SAMPLE_RATE'th one (and zero) is sampled: the samples store the
+// index of the basic block which contains it. Between two consecutive samples lie at most
+// SAMPLE_RATE ones, so the binary search over the L1 entries is limited to a few basic blocks,
+// instead of the whole bit vector. This is the "combined sampling" of cs-poppy: the samples only
+// narrow the search, the counters are shared with rank.
+//
+// The index needs 64 bits per 2048 bits, i.e. about 3.1% of the bits, plus 32 bits per
+// SAMPLE_RATE ones and zeros for the samples (another 0.4% with the default rate).
+pub struct PoppyVec<const SAMPLE_RATE: usize = 8192, const L0_BITS: usize = 32> {
+    bits: BitVector,
+    l0: Vec<u64>,
+    l1l2: Vec<u64>,
+    select_samples: [Vec<u32>; 2],
+    count1: usize,
+}
+
+const BASIC_BLOCK_BITS: usize = 2048;
+const SUB_BLOCK_BITS: usize = 512;
+const L1_MASK: u64 = (1 << 32) - 1;
+
+impl<const SAMPLE_RATE: usize, const L0_BITS: usize> PoppyVec<SAMPLE_RATE, L0_BITS> {
+    // Smaller L0 blocks are only useful for testing, bigger ones do not fit the 32-bit L1 counters.
+    const CHECK_PARAMS: () = {
+        assert!(SAMPLE_RATE > 0, "SAMPLE_RATE must be positive");
+        assert!(L0_BITS >= 11 && L0_BITS <= 32, "L0_BITS must be between 11 and 32");
+    };
+
+    pub fn size(&self) -> usize {
+        self.bits.size()
+    }
+
+    fn nr_basic_blocks(&self) -> usize {
+        self.l1l2.len()
+    }
+
+    // Number of set bits in the sub-blocks of basic block `block` before sub-block `sub`.
+    #[inline(always)]
+    fn l2_prefix(&self, block: usize, sub: usize) -> usize {
+        let entry = self.l1l2[block] >> 32;
+        (0..sub).map(|j| ((entry >> (10 * j)) & 0x3FF) as usize).sum()
+    }
+
+    // Number of set bits before basic block `block`.
+    #[inline(always)]
+    fn ones_before_block(&self, block: usize) -> usize {
+        let upper = (block * BASIC_BLOCK_BITS) >> L0_BITS;
+        self.l0[upper] as usize + (self.l1l2[block] & L1_MASK) as usize
+    }
+
+    // Number of bits with value x before basic block `block`.
+    fn value_count_before_block(&self, block: usize, x: u32) -> usize {
+        let ones = self.ones_before_block(block);
+        if x == 1 { ones } else { block * BASIC_BLOCK_BITS - ones }
+    }
+
+    fn generic_select(&self, nth: usize, x: u32) -> Option<usize> {
+        let total = if x == 1 { self.count1 } else { self.size() - self.count1 };
+        if nth == 0 || nth > total {
+            return None;
+        }
+
+        // The nth such bit is between the samples of the (k * SAMPLE_RATE + 1)'th and the
+        // ((k + 1) * SAMPLE_RATE + 1)'th one.
+        let samples = &self.select_samples[x as usize];
+        let k = (nth - 1) / SAMPLE_RATE;
+        let mut l = samples[k] as usize;
+        let mut r = samples.get(k + 1).map_or(self.nr_basic_blocks() - 1, |s| *s as usize);
+
+        // Last basic block with less than nth such bits before it.
+        while l < r {
+            let mid = (l + r).div_ceil(2);
+            if self.value_count_before_block(mid, x) < nth {
+                l = mid;
+            } else {
+                r = mid - 1;
+            }
+        }
+
+        let block = l;
+        let mut in_block = nth - self.value_count_before_block(block, x);
+        let mut sub = 0;
+        while sub < 3 {
+            let ones = self.l2_prefix(block, sub + 1) - self.l2_prefix(block, sub);
+            let count = if x == 1 { ones } else { SUB_BLOCK_BITS - ones };
+            if in_block <= count {
+                break;
+            }
+            in_block -= count;
+            sub += 1;
+        }
+
+        self.bits.find_nth_x(block * BASIC_BLOCK_BITS + sub * SUB_BLOCK_BITS, in_block, x)
+    }
+
+    fn init_select_samples(&mut self) {
+        for x in 0..2u32 {
+            let total = if x == 1 { self.count1 } else { self.size() - self.count1 };
+            let mut samples = Vec::with_capacity(total.div_ceil(SAMPLE_RATE));
+
+            // Sample i is the basic block which contains the (i * SAMPLE_RATE + 1)'th such bit.
+            let mut block = 0;
+            for nth in (1..=total).step_by(SAMPLE_RATE) {
+                while block + 1 < self.nr_basic_blocks() && self.value_count_before_block(block + 1, x) < nth {
+                    block += 1;
+                }
+                samples.push(block as u32);
+            }
+            self.select_samples[x as usize] = samples;
+        }
+    }
+}
+
+impl<const SAMPLE_RATE: usize, const L0_BITS: usize> RankSelectVector for PoppyVec<SAMPLE_RATE, L0_BITS> {
+    fn new(bits: BitVector) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let _ = Self::CHECK_PARAMS;
+
+        let size = bits.size();
+        let nr_basic_blocks = size.div_ceil(BASIC_BLOCK_BITS);
+        assert!(nr_basic_blocks <= u32::MAX as usize, "bit vector is too big for the select samples");
+
+        let mut l0 = Vec::with_capacity(size.div_ceil(1 << L0_BITS));
+        let mut l1l2 = Vec::with_capacity(nr_basic_blocks);
+        let mut count1 = 0;
+        for block in 0..nr_basic_blocks {
+            let start = block * BASIC_BLOCK_BITS;
+            if start.is_multiple_of(1 << L0_BITS) {
+                l0.push(count1 as u64);
+            }
+
+            let mut entry = (count1 - *l0.last().unwrap() as usize) as u64;
+            for sub in 0..4 {
+                // The last basic block may end early, its L2 counters only count the bits up to the
+                // end of the bit vector.
+                let l = (start + sub * SUB_BLOCK_BITS).min(size);
+                let r = (start + (sub + 1) * SUB_BLOCK_BITS).min(size);
+                let ones = bits.count_ones(l, r);
+                if sub < 3 {
+                    entry |= (ones as u64) << (32 + 10 * sub);
+                }
+                count1 += ones;
+            }
+            l1l2.push(entry);
+        }
+
+        let mut poppy = PoppyVec {
+            bits,
+            l0,
+            l1l2,
+            select_samples: [Vec::new(), Vec::new()],
+            count1,
+        };
+        poppy.init_select_samples();
+        poppy
+    }
+
+    fn select1(&self, i: usize) -> Option<usize> {
+        self.generic_select(i, 1)
+    }
+
+    fn select0(&self, i: usize) -> Option<usize> {
+        self.generic_select(i, 0)
+    }
+
+    fn rank(&self, i: usize) -> usize {
+        assert!(i <= self.size());
+        if i == self.size() {
+            return self.count1;
+        }
+
+        let block = i / BASIC_BLOCK_BITS;
+        let sub = (i % BASIC_BLOCK_BITS) / SUB_BLOCK_BITS;
+        let start = block * BASIC_BLOCK_BITS + sub * SUB_BLOCK_BITS;
+        self.ones_before_block(block) + self.l2_prefix(block, sub) + self.bits.count_ones(start, i)
+    }
+
+    fn access(&self, i: usize) -> u32 {
+        self.bits.get_nth(i)
+    }
+
+    // The L0 counters, the L1/L2 entries and the select samples, about 3.5% of the bits with the
+    // default rate. The bits themselves are not included.
+    fn get_memory_usage(&self) -> usize {
+        (self.l0.len() + self.l1l2.len()) * std::mem::size_of::<u64>() +
+            self.select_samples.iter().map(|s| s.len() * std::mem::size_of::<u32>()).sum::<usize>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tst::*;
+
+    #[test]
+    fn sample() {
+        test_sample::<PoppyVec>();
+        test_sample::<PoppyVec<1, 11>>();
+    }
+
+    #[test]
+    fn select() {
+        test_simple_select::<PoppyVec>();
+        test_simple_select::<PoppyVec<3, 11>>();
+    }
+
+    #[test]
+    fn random() {
+        test_random_sizes::<PoppyVec>(BASIC_BLOCK_BITS);
+        // Several L0 blocks and samples in most basic blocks.
+        test_random_sizes::<PoppyVec<7, 12>>(BASIC_BLOCK_BITS);
+        test_random_sizes::<PoppyVec<100, 11>>(BASIC_BLOCK_BITS);
+    }
+
+    #[test]
+    fn unspecified_bits_after_end() {
+        // The last sub-block is cut at the end of the bit vector when the L2 counters are computed.
+        test_bits_after_end::<PoppyVec>();
+    }
+}
@@ -0,0 +1,139 @@
+use crate::bvec::RankSelectVector;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Query {
+    Access(usize),
+    Select1(usize),
+    Select0(usize),
+    Rank1(usize),
+    Rank0(usize),
+    // Number of 1s in [l, r).
+    Count(usize, usize),
+    // First bit with the value at a position >= p.
+    Next1(usize),
+    Next0(usize),
+    // Last bit with the value at a position <= p.
+    Prev1(usize),
+    Prev0(usize),
+    // The k'th bit with the value at a position >= p, as (p, k).
+    SelectFrom1(usize, usize),
+    SelectFrom0(usize, usize),
+}
+
+// The query in the syntax of the input files, see parser.rs.
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Query::Access(i) => write!(f, "access {}", i),
+            Query::Select1(i) => write!(f, "select 1 {}", i),
+            Query::Select0(i) => write!(f, "select 0 {}", i),
+            Query::Rank1(i) => write!(f, "rank 1 {}", i),
+            Query::Rank0(i) => write!(f, "rank 0 {}", i),
+            Query::Count(l, r) => write!(f, "count {} {}", l, r),
+            Query::Next1(p) => write!(f, "next 1 {}", p),
+            Query::Next0(p) => write!(f, "next 0 {}", p),
+            Query::Prev1(p) => write!(f, "prev 1 {}", p),
+            Query::Prev0(p) => write!(f, "prev 0 {}", p),
+            Query::SelectFrom1(p, k) => write!(f, "select_from 1 {} {}", p, k),
+            Query::SelectFrom0(p, k) => write!(f, "select_from 0 {} {}", p, k),
+        }
+    }
+}
+
+pub trait ExecQueries {
+    fn exec_queries<'a>(self, b: &'a impl RankSelectVector) -> impl Iterator<Item = usize> + 'a where Self: 'a;
+}
+
+// Answer a single query. Select, next, prev and select_from queries which have no answer return
+// usize::MAX.
+pub fn exec_one_query(q: &Query, b: &impl RankSelectVector) -> usize {
+    match q {
+        Query::Access(i) => b.access(*i) as usize,
+        Query::Select1(i) => b.select1(*i).unwrap_or(usize::MAX),
+        Query::Select0(i) => b.select0(*i).unwrap_or(usize::MAX),
+        Query::Rank1(i) => b.rank(*i),
+        Query::Rank0(i) => i - b.rank(*i),
+        Query::Count(l, r) => b.count_range(*l, *r),
+        Query::Next1(p) => b.next(1, *p).unwrap_or(usize::MAX),
+        Query::Next0(p) => b.next(0, *p).unwrap_or(usize::MAX),
+        Query::Prev1(p) => b.prev(1, *p).unwrap_or(usize::MAX),
+        Query::Prev0(p) => b.prev(0, *p).unwrap_or(usize::MAX),
+        Query::SelectFrom1(p, k) => b.select_from(1, *p, *k).unwrap_or(usize::MAX),
+        Query::SelectFrom0(p, k) => b.select_from(0, *p, *k).unwrap_or(usize::MAX),
+    }
+
+}
+
+impl<'b, I: Iterator<Item = &'b Query>> ExecQueries for I {
+    fn exec_queries<'a>(self, b: &'a impl RankSelectVector) -> impl Iterator<Item = usize> + 'a where I: 'a {
+        self.map(|q| {
+            exec_one_query(q, b)
+        })
+    }
+}
+
+// Answers a slice of queries at once, in the same order as ExecQueries.
+//
+// Queries on a large bit vector are dominated by cache misses. Answering them one after the other
+// waits for every miss before the next query starts. Instead, while query k is answered, the memory
+// of query k + PREFETCH_DISTANCE is prefetched with RankSelectVector::prefetch, so that several
+// misses are in flight at the same time.
+//
+// The queries are not reordered: sorting them by position makes the accesses more local, but for
+// random queries the sort costs more time than it saves.
+pub trait ExecBatch {
+    fn exec_batch(&self, qs: &[Query]) -> Vec<usize>;
+}
+
+const PREFETCH_DISTANCE: usize = 16;
+
+impl<V: RankSelectVector> ExecBatch for V {
+    fn exec_batch(&self, qs: &[Query]) -> Vec<usize> {
+        for q in qs.iter().take(PREFETCH_DISTANCE) {
+            self.prefetch(q);
+        }
+
+        let mut answers = Vec::with_capacity(qs.len());
+        for (k, q) in qs.iter().enumerate() {
+            if let Some(next) = qs.get(k + PREFETCH_DISTANCE) {
+                self.prefetch(next);
+            }
+            answers.push(exec_one_query(q, self));
+        }
+        answers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bvec::BitVector;
+    use crate::config::RankSelectConfig;
+    use crate::fast_bvec::{DefaultParams, FastRASBVec, Params, RuntimeRASBVec};
+    use crate::rank9::Rank9Vec;
+    use crate::tst::*;
+
+    fn check_batch(b: &impl RankSelectVector, qs: &[Query]) {
+        let sequential = qs.iter().exec_queries(b).collect::<Vec<_>>();
+        assert_eq!(b.exec_batch(qs), sequential);
+        // Fewer queries than the prefetch distance.
+        assert_eq!(b.exec_batch(&qs[..5]), sequential[..5]);
+        assert_eq!(b.exec_batch(&[]), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn exec_batch() {
+        for (size, seed) in [(64, 2), (4096, 3), (100000, 4), (70001, 5)] {
+            let bits = generate_random_bits_string(size, seed, 0.5);
+            let bv = BitVector::new_from_string(&bits);
+            let qs = generate_random_queries(1000, seed, size, Some(bv.rank(size)));
+
+            check_batch(&bv, &qs);
+            check_batch(&FastRASBVec::<DefaultParams>::new(bv.clone()), &qs);
+            check_batch(&FastRASBVec::<Params<256, 1024, 4, 40, 8>>::new(bv.clone()), &qs);
+            check_batch(&RuntimeRASBVec::with_config(bv.clone(), RankSelectConfig::of::<Params<512, 2048>>().unwrap()).unwrap(), &qs);
+            check_batch(&Rank9Vec::new(bv), &qs);
+        }
+    }
+}
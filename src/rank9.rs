@@ -0,0 +1,210 @@
+use crate::broadword::select_in_word;
+use crate::bvec::{BitCell, BitVector, RankSelectVector, BIT_CELL_SIZE};
+use crate::eytzinger::prefetch;
+use crate::query::Query;
+
+// Rank9 (Vigna, "Broadword Implementation of Rank/Select Queries") with the counters interleaved
+// with the bits.
+//
+// The bits are split into basic blocks of 512 bits (8 words). Every basic block is stored as one
+// record of 10 words:
+//
+//   word 0      number of set bits before the basic block (absolute counter)
+//   word 1      7 relative counters of 9 bits each: counter j (1 <= j <= 7) at bits 9 * (j - 1) is the
+//               number of set bits in the first j words of the basic block
+//   word 2..10  the 8 words of the basic block
+//
+// In FastRASBVec, the RankSuperblocks and the bits are in separate arrays, so a rank query touches
+// (at least) two unrelated cache lines. Here the counters and the bits they describe are in the same
+// 80 byte record, i.e. in the same or the next cache line, which the hardware prefetcher loads
+// together.
+//
+// rank is one counter lookup plus one popcount. select binary searches the absolute counters, then
+// uses the relative counters to find the word.
+pub struct Rank9Vec {
+    records: Vec<u64>,
+    size: usize,
+    count1: usize,
+}
+
+const WORDS_PER_BLOCK: usize = 8;
+const BLOCK_BITS: usize = WORDS_PER_BLOCK * BIT_CELL_SIZE;
+const RECORD_WORDS: usize = WORDS_PER_BLOCK + 2;
+
+impl Rank9Vec {
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn nr_blocks(&self) -> usize {
+        self.records.len() / RECORD_WORDS
+    }
+
+    #[inline(always)]
+    fn word(&self, i: usize) -> BitCell {
+        let (block, word) = (i / WORDS_PER_BLOCK, i % WORDS_PER_BLOCK);
+        self.records[block * RECORD_WORDS + 2 + word]
+    }
+
+    // Number of set bits in the first `word` words of basic block `block`, from the relative counters.
+    #[inline(always)]
+    fn relative(&self, block: usize, word: usize) -> usize {
+        if word == 0 {
+            return 0;
+        }
+        ((self.records[block * RECORD_WORDS + 1] >> (9 * (word - 1))) & 0x1FF) as usize
+    }
+
+    // Number of bits with value x before basic block `block`.
+    fn value_count_before_block(&self, block: usize, x: u32) -> usize {
+        let ones = self.records[block * RECORD_WORDS] as usize;
+        if x == 1 { ones } else { block * BLOCK_BITS - ones }
+    }
+
+    fn generic_select(&self, nth: usize, x: u32) -> Option<usize> {
+        let total = if x == 1 { self.count1 } else { self.size - self.count1 };
+        if nth == 0 || nth > total {
+            return None;
+        }
+
+        // Last basic block with less than nth such bits before it.
+        let (mut l, mut r) = (0, self.nr_blocks() - 1);
+        while l < r {
+            let mid = (l + r).div_ceil(2);
+            if self.value_count_before_block(mid, x) < nth {
+                l = mid;
+            } else {
+                r = mid - 1;
+            }
+        }
+
+        let block = l;
+        let in_block = nth - self.value_count_before_block(block, x);
+        let value_count_before_word = |word: usize| {
+            let ones = self.relative(block, word);
+            if x == 1 { ones } else { word * BIT_CELL_SIZE - ones }
+        };
+
+        let mut word = 0;
+        while word + 1 < WORDS_PER_BLOCK && value_count_before_word(word + 1) < in_block {
+            word += 1;
+        }
+        let before = value_count_before_word(word);
+
+        let mut w = self.word(block * WORDS_PER_BLOCK + word);
+        if x == 0 {
+            w = !w;
+        }
+        Some(block * BLOCK_BITS + word * BIT_CELL_SIZE + select_in_word(w, in_block - before))
+    }
+}
+
+impl RankSelectVector for Rank9Vec {
+    fn new(bits: BitVector) -> Self {
+        let size = bits.size();
+        let nr_blocks = size.div_ceil(BLOCK_BITS);
+
+        let mut records = Vec::with_capacity(nr_blocks * RECORD_WORDS);
+        let mut count1 = 0;
+        for block in 0..nr_blocks {
+            let mut data = [0; WORDS_PER_BLOCK];
+            for (j, word) in data.iter_mut().enumerate() {
+                let idx = block * WORDS_PER_BLOCK + j;
+                if idx * BIT_CELL_SIZE < size {
+                    // Bits past the end of the bit vector are unspecified in the last word.
+                    *word = bits.get_bits(idx * BIT_CELL_SIZE, BIT_CELL_SIZE.min(size - idx * BIT_CELL_SIZE));
+                }
+            }
+
+            let mut relative = 0u64;
+            let mut in_block = 0;
+            for j in 0..WORDS_PER_BLOCK {
+                in_block += data[j].count_ones() as u64;
+                if j + 1 < WORDS_PER_BLOCK {
+                    relative |= in_block << (9 * j);
+                }
+            }
+
+            records.push(count1 as u64);
+            records.push(relative);
+            records.extend_from_slice(&data);
+            count1 += in_block as usize;
+        }
+
+        Rank9Vec {
+            records,
+            size,
+            count1,
+        }
+    }
+
+    fn select1(&self, i: usize) -> Option<usize> {
+        self.generic_select(i, 1)
+    }
+
+    fn select0(&self, i: usize) -> Option<usize> {
+        self.generic_select(i, 0)
+    }
+
+    fn rank(&self, i: usize) -> usize {
+        assert!(i <= self.size);
+        if i == self.size {
+            return self.count1;
+        }
+
+        let (word_idx, offset) = (i / BIT_CELL_SIZE, i % BIT_CELL_SIZE);
+        let (block, word) = (word_idx / WORDS_PER_BLOCK, word_idx % WORDS_PER_BLOCK);
+        let mut r = self.records[block * RECORD_WORDS] as usize + self.relative(block, word);
+        if offset > 0 {
+            r += (self.word(word_idx) & (((1 as BitCell) << offset) - 1)).count_ones() as usize;
+        }
+        r
+    }
+
+    fn access(&self, i: usize) -> u32 {
+        assert!(i < self.size);
+        ((self.word(i / BIT_CELL_SIZE) >> (i % BIT_CELL_SIZE)) & 1) as u32
+    }
+
+    // The counters and the word are in the same record, which spans at most two cache lines.
+    fn prefetch(&self, q: &Query) {
+        if let Query::Access(i) | Query::Rank0(i) | Query::Rank1(i) | Query::Count(_, i) | Query::Next0(i) | Query::Next1(i) |
+            Query::Prev0(i) | Query::Prev1(i) | Query::SelectFrom0(i, _) | Query::SelectFrom1(i, _) = *q {
+            let word_idx = i / BIT_CELL_SIZE;
+            let record = self.records.as_ptr().wrapping_add(word_idx / WORDS_PER_BLOCK * RECORD_WORDS);
+            prefetch(record);
+            prefetch(record.wrapping_add(2 + word_idx % WORDS_PER_BLOCK));
+        }
+    }
+
+    // Like FastRASBVec, only the memory for the counters is reported, not the bits themselves.
+    fn get_memory_usage(&self) -> usize {
+        self.nr_blocks() * 2 * std::mem::size_of::<u64>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tst::*;
+
+    #[test]
+    fn sample() {
+        test_sample::<Rank9Vec>();
+    }
+
+    #[test]
+    fn select() {
+        test_simple_select::<Rank9Vec>();
+    }
+
+    #[test]
+    fn random() {
+        test_random_sizes::<Rank9Vec>(512);
+    }
+
+    #[test]
+    fn unspecified_bits_after_end() {
+        test_bits_after_end::<Rank9Vec>();
+    }
+}
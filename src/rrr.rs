@@ -0,0 +1,325 @@
+use crate::broadword::select_in_word;
+use crate::bvec::{BitVector, RankSelectVector};
+use crate::int_vec::IntVector;
+
+// RRR (Raman, Raman, Rao) compressed bit vector.
+//
+// The bits are split into blocks of BLOCK_SIZE bits. Each block is stored as a pair (class, offset),
+// where the class is the number of set bits in the block and the offset is the index of the block
+// among all blocks with this class (in the combinatorial number system). Classes take
+// log(BLOCK_SIZE + 1) bits each, offsets take log(BLOCK_SIZE choose class) bits, so blocks which are
+// almost all zeros or all ones take very little space. The total size is close to the zero-order
+// entropy H0 of the bit vector.
+//
+// Every SAMPLE_RATE blocks, we store the number of set bits before the block and the position of its
+// offset in the offset stream. Queries go to the closest sample before them and then scan at most
+// SAMPLE_RATE classes, so larger sample rates use less memory but make queries slower.
+pub struct RRRVec<const BLOCK_SIZE: usize = 63, const SAMPLE_RATE: usize = 32> {
+    classes: IntVector,
+    offsets: Vec<u64>,
+    // Number of set bits before the blocks with index k * SAMPLE_RATE.
+    rank_samples: Vec<usize>,
+    // Position in `offsets` (in bits) of the blocks with index k * SAMPLE_RATE.
+    offset_samples: Vec<usize>,
+    size: usize,
+    count1: usize,
+}
+
+// BINOMIAL[n][k] = n choose k, for n < 64. The largest value, 63 choose 31, still fits in a u64.
+static BINOMIAL: [[u64; 64]; 64] = {
+    let mut table = [[0u64; 64]; 64];
+    let mut n = 0;
+    while n < 64 {
+        table[n][0] = 1;
+        let mut k = 1;
+        while k <= n {
+            table[n][k] = table[n - 1][k - 1] + table[n - 1][k];
+            k += 1;
+        }
+        n += 1;
+    }
+    table
+};
+
+// Number of bits needed to store an offset for a block of `block_size` bits with `class` set bits.
+fn offset_width(block_size: usize, class: usize) -> usize {
+    let count = BINOMIAL[block_size][class];
+    (u64::BITS - (count - 1).leading_zeros()) as usize
+}
+
+// Index of `block` among all blocks with the same number of set bits.
+fn encode_block(block_size: usize, block: u64) -> u64 {
+    let mut class = block.count_ones() as usize;
+    let mut offset = 0;
+    for i in (0..block_size).rev() {
+        if class == 0 {
+            break;
+        }
+
+        if (block >> i) & 1 == 1 {
+            offset += BINOMIAL[i][class];
+            class -= 1;
+        }
+    }
+    offset
+}
+
+// Inverse of encode_block.
+fn decode_block(block_size: usize, mut class: usize, mut offset: u64) -> u64 {
+    let mut block = 0;
+    for i in (0..block_size).rev() {
+        if class == 0 {
+            break;
+        }
+
+        if offset >= BINOMIAL[i][class] {
+            block |= 1 << i;
+            offset -= BINOMIAL[i][class];
+            class -= 1;
+        }
+    }
+    block
+}
+
+fn mask(width: usize) -> u64 {
+    if width == 64 { u64::MAX } else { (1u64 << width) - 1 }
+}
+
+impl<const BLOCK_SIZE: usize, const SAMPLE_RATE: usize> RRRVec<BLOCK_SIZE, SAMPLE_RATE> {
+    const CLASS_WIDTH: usize = (usize::BITS - BLOCK_SIZE.leading_zeros()) as usize;
+
+    // Read `width` bits from the offset stream, starting at bit `pos`.
+    fn read_offset(&self, pos: usize, width: usize) -> u64 {
+        if width == 0 {
+            return 0;
+        }
+
+        let (word, bit) = (pos / 64, pos % 64);
+        let mut value = self.offsets[word] >> bit;
+        if bit + width > 64 {
+            value |= self.offsets[word + 1] << (64 - bit);
+        }
+        value & mask(width)
+    }
+
+    // Decode the block with the given index, whose offset starts at `offset_pos`.
+    fn block(&self, idx: usize, offset_pos: usize) -> u64 {
+        let class = self.classes.get(idx) as usize;
+        decode_block(BLOCK_SIZE, class, self.read_offset(offset_pos, offset_width(BLOCK_SIZE, class)))
+    }
+
+    // Starting from the sample before block `idx`, skip over the blocks before it.
+    // Returns the number of set bits before block `idx` and the position of its offset.
+    fn scan_to_block(&self, idx: usize) -> (usize, usize) {
+        let sample = idx / SAMPLE_RATE;
+        let mut ones = self.rank_samples[sample];
+        let mut offset_pos = self.offset_samples[sample];
+        for k in sample * SAMPLE_RATE..idx {
+            let class = self.classes.get(k) as usize;
+            ones += class;
+            offset_pos += offset_width(BLOCK_SIZE, class);
+        }
+        (ones, offset_pos)
+    }
+
+    // Find the block containing the nth bit with value x, where 1 <= nth <= number of bits with value
+    // x. Returns the position of the bit.
+    fn generic_select(&self, nth: usize, x: u32) -> usize {
+        let count_before_sample = |k: usize| {
+            if x == 1 {
+                self.rank_samples[k]
+            } else {
+                k * SAMPLE_RATE * BLOCK_SIZE - self.rank_samples[k]
+            }
+        };
+
+        // Last sample with less than nth values before it. Sample 0 always qualifies.
+        let (mut l, mut r) = (0, self.rank_samples.len() - 1);
+        while l < r {
+            let mid = (l + r).div_ceil(2);
+            if count_before_sample(mid) < nth {
+                l = mid;
+            } else {
+                r = mid - 1;
+            }
+        }
+
+        let mut remaining = nth - count_before_sample(l);
+        let mut offset_pos = self.offset_samples[l];
+        let mut idx = l * SAMPLE_RATE;
+        loop {
+            let class = self.classes.get(idx) as usize;
+            let in_block = if x == 1 { class } else { BLOCK_SIZE - class };
+            if remaining <= in_block {
+                break;
+            }
+
+            remaining -= in_block;
+            offset_pos += offset_width(BLOCK_SIZE, class);
+            idx += 1;
+        }
+
+        let mut block = self.block(idx, offset_pos);
+        if x == 0 {
+            block = !block;
+        }
+        idx * BLOCK_SIZE + select_in_word(block, remaining)
+    }
+}
+
+impl<const BLOCK_SIZE: usize, const SAMPLE_RATE: usize> RankSelectVector for RRRVec<BLOCK_SIZE, SAMPLE_RATE> {
+    fn new(bits: BitVector) -> Self {
+        assert!((1..64).contains(&BLOCK_SIZE), "RRR block size must be between 1 and 63");
+        assert!(SAMPLE_RATE > 0, "RRR sample rate must be positive");
+
+        let size = bits.size();
+        let nr_blocks = size.div_ceil(BLOCK_SIZE);
+
+        let mut classes = IntVector::with_capacity(Self::CLASS_WIDTH, nr_blocks);
+        let mut offsets = vec![];
+        let mut rank_samples = vec![];
+        let mut offset_samples = vec![];
+
+        let mut count1 = 0;
+        let mut offset_pos = 0;
+        for idx in 0..=nr_blocks {
+            if idx % SAMPLE_RATE == 0 {
+                rank_samples.push(count1);
+                offset_samples.push(offset_pos);
+            }
+
+            if idx == nr_blocks {
+                break;
+            }
+
+            // The last block is padded with zeros.
+            let start = idx * BLOCK_SIZE;
+            let block = bits.get_bits(start, BLOCK_SIZE.min(size - start));
+            let class = block.count_ones() as usize;
+            classes.push(class as u64);
+            count1 += class;
+
+            let width = offset_width(BLOCK_SIZE, class);
+            if width > 0 {
+                let offset = encode_block(BLOCK_SIZE, block);
+                let (word, bit) = (offset_pos / 64, offset_pos % 64);
+                if offsets.len() < (offset_pos + width).div_ceil(64) {
+                    offsets.push(0);
+                }
+                offsets[word] |= offset << bit;
+                if bit + width > 64 {
+                    offsets[word + 1] |= offset >> (64 - bit);
+                }
+                offset_pos += width;
+            }
+        }
+
+        RRRVec {
+            classes,
+            offsets,
+            rank_samples,
+            offset_samples,
+            size,
+            count1,
+        }
+    }
+
+    fn select1(&self, i: usize) -> Option<usize> {
+        if i == 0 || i > self.count1 {
+            return None;
+        }
+        Some(self.generic_select(i, 1))
+    }
+
+    fn select0(&self, i: usize) -> Option<usize> {
+        if i == 0 || i > self.size - self.count1 {
+            return None;
+        }
+        Some(self.generic_select(i, 0))
+    }
+
+    fn rank(&self, i: usize) -> usize {
+        assert!(i <= self.size);
+        let (idx, rem) = (i / BLOCK_SIZE, i % BLOCK_SIZE);
+        let (ones, offset_pos) = self.scan_to_block(idx);
+        if rem == 0 {
+            return ones;
+        }
+
+        ones + (self.block(idx, offset_pos) & mask(rem)).count_ones() as usize
+    }
+
+    fn access(&self, i: usize) -> u32 {
+        assert!(i < self.size);
+        let idx = i / BLOCK_SIZE;
+        let (_, offset_pos) = self.scan_to_block(idx);
+        ((self.block(idx, offset_pos) >> (i % BLOCK_SIZE)) & 1) as u32
+    }
+
+    // This is the compressed size, the original bit vector is not kept.
+    fn get_memory_usage(&self) -> usize {
+        self.classes.get_memory_usage() +
+            self.offsets.len() * std::mem::size_of::<u64>() +
+            (self.rank_samples.len() + self.offset_samples.len()) * std::mem::size_of::<usize>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tst::*;
+
+    #[test]
+    fn encode_decode() {
+        for block_size in [1, 5, 15, 63] {
+            for block in [0u64, 1, 0b1011, 0x5555_5555_5555_5555, u64::MAX] {
+                let block = block & mask(block_size);
+                let class = block.count_ones() as usize;
+                let offset = encode_block(block_size, block);
+                assert!(offset < BINOMIAL[block_size][class]);
+                assert_eq!(decode_block(block_size, class, offset), block);
+            }
+        }
+    }
+
+    #[test]
+    fn sample() {
+        test_sample::<RRRVec>();
+        test_sample::<RRRVec<7, 2>>();
+    }
+
+    #[test]
+    fn select() {
+        test_simple_select::<RRRVec>();
+        test_simple_select::<RRRVec<15, 1>>();
+    }
+
+    fn compare_rrr_with_naive<V: RankSelectVector>(bits: &str, nr_queries: usize) {
+        let rrr = V::new(BitVector::new_from_string(bits));
+        compare_with_naive(&rrr, bits, nr_queries, 5);
+        assert_eq!(rrr.rank(bits.len()), bits.bytes().filter(|b| *b == b'1').count());
+    }
+
+    #[test]
+    fn random() {
+        let sections = [
+            SectionDescription { weight0: 0.99, section_len: 500, probability: 0.7 },
+            SectionDescription { weight0: 0.5, section_len: 100, probability: 0.3 },
+        ];
+        let skewed = generate_random_bits_in_sections(&sections, 40, 3);
+
+        for bits in [generate_random_bits_string(5000, 1, 0.5), skewed, generate_random_bits_string(4000, 2, 0.02)] {
+            compare_rrr_with_naive::<RRRVec>(&bits, 3000);
+            compare_rrr_with_naive::<RRRVec<31, 4>>(&bits, 3000);
+            compare_rrr_with_naive::<RRRVec<1, 1>>(&bits, 3000);
+        }
+    }
+
+    #[test]
+    fn compresses_skewed() {
+        let bits = BitVector::new_from_string(&generate_random_bits_string(100000, 4, 0.99));
+        let plain = bits.get_memory_usage();
+        let rrr = RRRVec::<63, 32>::new(bits);
+        assert!(rrr.get_memory_usage() * 2 < plain);
+    }
+}
@@ -0,0 +1,132 @@
+use memmap2::Mmap;
+use std::fmt;
+use std::io;
+use std::ops::Deref;
+use std::sync::Arc;
+
+// Plain integer types which can be viewed directly inside a mapped file: every bit pattern is a
+// valid value and they have no padding.
+pub trait Plain: Copy + fmt::Debug + 'static {}
+impl Plain for u8 {}
+impl Plain for u64 {}
+
+// Backing memory for the large arrays of the bit vectors (BitCells, RankSuperblock data).
+// It is either an owned Vec, or a range inside a memory-mapped file. The mapping is shared, so that
+// many structures (and, through the page cache, many processes) can use the same file without
+// copying it into memory.
+//
+// A pointer to the first element and the length are cached next to the owner, so that accessing the
+// data does not need to check which kind of storage is used. This matters because it happens in the
+// innermost loops of rank and select.
+//
+// Mapped storage is read-only. Mutating it first copies the data into an owned Vec.
+pub struct Storage<T: Plain> {
+    ptr: *const T,
+    len: usize,
+    owner: Owner<T>,
+}
+
+#[derive(Clone)]
+enum Owner<T: Plain> {
+    Vec(Vec<T>),
+    Mapped(Arc<Mmap>),
+}
+
+// SAFETY: Storage is an immutable view into memory owned by `owner` (a Vec or a read-only mapping),
+// which are both Send + Sync. Mutation always goes through &mut self.
+unsafe impl<T: Plain + Send> Send for Storage<T> {}
+unsafe impl<T: Plain + Sync> Sync for Storage<T> {}
+
+impl<T: Plain> Storage<T> {
+    // View `len` elements of type T starting at byte `offset` of `map`. Fails if the range is not
+    // inside the mapping or if it is not aligned for T.
+    pub fn mapped(map: Arc<Mmap>, offset: usize, len: usize) -> io::Result<Self> {
+        let bytes = len.checked_mul(std::mem::size_of::<T>())
+            .and_then(|n| n.checked_add(offset))
+            .filter(|end| *end <= map.len());
+        if bytes.is_none() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "mapped range is past the end of the file"));
+        }
+
+        if !(map.as_ptr() as usize + offset).is_multiple_of(std::mem::align_of::<T>()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "mapped range is not aligned"));
+        }
+
+        // SAFETY: offset is inside the mapping, checked above.
+        let ptr = unsafe { map.as_ptr().add(offset) } as *const T;
+        Ok(Storage { ptr, len, owner: Owner::Mapped(map) })
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        matches!(self.owner, Owner::Mapped(_))
+    }
+
+    // Run `f` with mutable access to the data as a Vec, copying it out of the mapping first if
+    // needed.
+    pub fn with_vec<R>(&mut self, f: impl FnOnce(&mut Vec<T>) -> R) -> R {
+        if self.is_mapped() {
+            *self = Storage::from(self.to_vec());
+        }
+
+        let Owner::Vec(v) = &mut self.owner else {
+            unreachable!();
+        };
+
+        let result = f(v);
+        // The Vec may have been reallocated.
+        self.ptr = v.as_mut_ptr();
+        self.len = v.len();
+        result
+    }
+
+    // Mutable access to the data, copying it out of the mapping first if needed.
+    pub fn make_mut(&mut self) -> &mut [T] {
+        self.with_vec(|_| ());
+        let Owner::Vec(v) = &mut self.owner else {
+            unreachable!();
+        };
+
+        let ptr = v.as_mut_ptr();
+        self.ptr = ptr;
+        // SAFETY: ptr and len describe the data of the Vec, which stays borrowed through `self` for
+        // the lifetime of the returned slice.
+        unsafe {
+            std::slice::from_raw_parts_mut(ptr, self.len)
+        }
+    }
+}
+
+impl<T: Plain> Deref for Storage<T> {
+    type Target = [T];
+
+    #[inline(always)]
+    fn deref(&self) -> &[T] {
+        // SAFETY: ptr and len describe the data of the Vec or the range of the mapping, checked in
+        // `mapped`. T is Plain, so any bytes are a valid [T]. The owner keeps the memory alive for
+        // the lifetime of the returned slice.
+        unsafe {
+            std::slice::from_raw_parts(self.ptr, self.len)
+        }
+    }
+}
+
+impl<T: Plain> Clone for Storage<T> {
+    fn clone(&self) -> Self {
+        match &self.owner {
+            Owner::Vec(v) => Storage::from(v.clone()),
+            Owner::Mapped(map) => Storage { ptr: self.ptr, len: self.len, owner: Owner::Mapped(map.clone()) },
+        }
+    }
+}
+
+impl<T: Plain> From<Vec<T>> for Storage<T> {
+    fn from(v: Vec<T>) -> Self {
+        Storage { ptr: v.as_ptr(), len: v.len(), owner: Owner::Vec(v) }
+    }
+}
+
+impl<T: Plain> fmt::Debug for Storage<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.deref().fmt(f)
+    }
+}
@@ -1,4 +1,5 @@
 use crate::bvec::{self};
+use crate::query::{ExecQueries, Query};
 use rand::{Rng, SeedableRng};
 use rand_xoshiro::Xoshiro256Plus;
 
@@ -42,69 +43,89 @@ pub fn generate_random_bits_string(length: usize, seed: u64, weight0: f32) -> St
     generate_random_bits_in_sections(&desc, 1, seed)
 }
 
-#[derive(Debug)]
-pub enum Query {
-    Access(usize),
-    Select1(usize),
-    Select0(usize),
-    Rank1(usize),
-    Rank0(usize),
-}
-
 pub fn generate_random_queries(nr_queries: usize, seed: u64, n: usize, count1: Option<usize>) -> Vec<Query> {
+    // Without any 1 (or 0) the select queries ask for the first one, which has no answer.
+    let select_range = |count: usize| if count == 0 { 1..2 } else { 0..count };
     let full_range = 0..n;
-    let range1 = 0..count1.unwrap_or(n);
-    let range0 = 0..(n - count1.unwrap_or(0));
+    let range1 = select_range(count1.unwrap_or(n));
+    let range0 = select_range(n - count1.unwrap_or(0));
 
     let mut rng = Xoshiro256Plus::seed_from_u64(seed);
     (0..nr_queries).map(|_| {
-        let qtype = rng.gen_range(0..5);
+        let qtype = rng.gen_range(0..12);
         match qtype {
             0 => Query::Access(rng.gen_range(full_range.clone())),
             1 => Query::Select1(rng.gen_range(range1.clone())),
             2 => Query::Select0(rng.gen_range(range0.clone())),
             3 => Query::Rank1(rng.gen_range(full_range.clone())),
             4 => Query::Rank0(rng.gen_range(full_range.clone())),
+            5 => {
+                let (a, b) = (rng.gen_range(0..=n), rng.gen_range(0..=n));
+                Query::Count(a.min(b), a.max(b))
+            },
+            6 => Query::Next1(rng.gen_range(full_range.clone())),
+            7 => Query::Next0(rng.gen_range(full_range.clone())),
+            8 => Query::Prev1(rng.gen_range(full_range.clone())),
+            9 => Query::Prev0(rng.gen_range(full_range.clone())),
+            10 => Query::SelectFrom1(rng.gen_range(full_range.clone()), rng.gen_range(range1.clone())),
+            11 => Query::SelectFrom0(rng.gen_range(full_range.clone()), rng.gen_range(range0.clone())),
             _ => panic!()
         }
     }).collect()
 }
 
-pub trait ExecQueries {
-    fn exec_queries<'a>(self, b: &'a impl bvec::RankSelectVector) -> impl Iterator<Item = usize> + 'a where Self: 'a;
-}
-
-pub fn exec_one_query(q: &Query, b: &impl bvec::RankSelectVector) -> usize {
-    match q {
-        Query::Access(i) => b.access(*i) as usize,
-        Query::Select1(i) => b.select1(*i).unwrap_or(usize::MAX),
-        Query::Select0(i) => b.select0(*i).unwrap_or(usize::MAX),
-        Query::Rank1(i) => b.rank(*i),
-        Query::Rank0(i) => i - b.rank(*i),
+pub fn check_answers(b: &impl bvec::RankSelectVector, qs: &[Query], answers: &[usize]) {
+    let vals = qs.iter().exec_queries(b).collect::<Vec<_>>();
+    assert_eq!(vals.len(), answers.len());
+    for (idx, val) in vals.iter().enumerate() {
+        let a = answers[idx];
+        assert!(*val == a, "expected {a}, got {val} for idx={idx} q={:?}", qs[idx]);
     }
+}
 
+// Check the answers of `b`, built from `bits`, for `nr_queries` random queries against the naive
+// BitVector.
+pub fn compare_with_naive(b: &impl bvec::RankSelectVector, bits: &str, nr_queries: usize, seed: u64) {
+    let naive = bvec::BitVector::new_from_string(bits);
+    let ones = bvec::RankSelectVector::rank(&naive, bits.len());
+    let queries = generate_random_queries(nr_queries, seed, bits.len(), Some(ones));
+    let expected = queries.iter().exec_queries(&naive).collect::<Vec<_>>();
+    check_answers(b, &queries, &expected);
 }
 
-impl<'b, I: Iterator<Item = &'b Query>> ExecQueries for I {
-    fn exec_queries<'a>(self, b: &'a impl bvec::RankSelectVector) -> impl Iterator<Item = usize> + 'a where I: 'a {
-        self.map(|q| {
-            exec_one_query(q, b)
-        })
+// Compare `T` with the naive BitVector on random bit vectors around the size `block_bits` of its
+// blocks: a single bit, one bit less than a block, exactly one block, many blocks, and many blocks
+// which are almost all 0s or all 1s. rank is checked at every position.
+pub fn test_random_sizes<T: bvec::RankSelectVector>(block_bits: usize) {
+    let sizes = [(1, 0.5, 1), (block_bits - 1, 0.5, 2), (block_bits, 0.3, 3), (10 * block_bits, 0.5, 4),
+                 (8 * block_bits, 0.99, 5), (6 * block_bits, 0.01, 6)];
+    for (size, weight0, seed) in sizes {
+        let bits = generate_random_bits_string(size, seed, weight0);
+        let b = T::new(bvec::BitVector::new_from_string(&bits));
+
+        let mut ones = 0;
+        for (i, c) in bits.chars().chain(Some('0')).enumerate() {
+            assert_eq!(b.rank(i), ones, "rank({}) with size {}", i, size);
+            ones += (c == '1') as usize;
+        }
+
+        compare_with_naive(&b, &bits, 3000, seed);
     }
 }
 
-pub fn check_answers(b: &impl bvec::RankSelectVector, qs: &Vec<Query>, answers: &Vec<usize>) {
-    let vals = qs.iter().exec_queries(b).collect::<Vec<_>>();
-    assert_eq!(vals.len(), answers.len());
-    for (idx, val) in vals.iter().enumerate() {
-        let a = answers[idx];
-        assert!(*val == a, "expected {a}, got {val} for idx={idx} q={:?}", qs[idx]);
-    }
+// BitVector::generate_random fills the whole last word with random bits. `T` must ignore the ones
+// after the end of the bit vector.
+pub fn test_bits_after_end<T: bvec::RankSelectVector>() {
+    let bits = bvec::BitVector::generate_random(100, 3);
+    let b = T::new(bits.clone());
+    let ones = bvec::RankSelectVector::rank(&bits, 100);
+    assert_eq!(b.rank(100), ones);
+    assert_eq!(b.select0(100 - ones + 1), None);
 }
 
 pub fn test_sample<T: bvec::RankSelectVector>() {
     let b = T::new(bvec::BitVector::new_from_string("001110110101010111111111"));
-    let qs = vec![
+    let qs = [
         Query::Access(4),
         Query::Rank0(10),
         Query::Select1(14),
@@ -113,7 +134,7 @@ pub fn test_sample<T: bvec::RankSelectVector>() {
         Query::Access(5),
     ];
 
-    check_answers(&b, &qs, &vec![1, 4, 20, 6, 5, 0]);
+    check_answers(&b, &qs, &[1, 4, 20, 6, 5, 0]);
 }
 
 pub fn test_simple_select<T: bvec::RankSelectVector>() {
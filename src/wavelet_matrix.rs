@@ -0,0 +1,271 @@
+use crate::bvec::{BitCell, BitVector, RankSelectVector, BIT_CELL_SIZE};
+use crate::fast_bvec::{DefaultParams, FastRASBVec, RASBVecParameters};
+
+// Wavelet matrix over a sequence of integers with `width` bits each.
+//
+// There is one bit vector per bit of the values, starting with the most significant bit. Level l
+// contains bit (width - 1 - l) of every value, with the values ordered by a stable partition of the
+// previous level: all values with a 0 bit come first (there are zeros[l] of them), followed by all
+// values with a 1 bit. A position i on level l therefore moves to rank0(i) on the next level if its
+// bit is 0, and to zeros[l] + rank1(i) if it is 1.
+//
+// All queries take O(width) rank (or select) queries on the levels.
+pub struct WaveletMatrix<Parameters: RASBVecParameters = DefaultParams> {
+    levels: Vec<FastRASBVec<Parameters>>,
+    zeros: Vec<usize>,
+    width: usize,
+    len: usize,
+}
+
+impl<Parameters: RASBVecParameters> WaveletMatrix<Parameters> {
+    // Build the wavelet matrix for `values`, which must all fit in `width` bits (at most 64).
+    pub fn new(values: &[u64], width: usize) -> Self {
+        assert!(width <= 64, "WaveletMatrix supports at most 64 bits per value");
+
+        let len = values.len();
+        let mut levels = Vec::with_capacity(width);
+        let mut zeros = Vec::with_capacity(width);
+
+        let mut cur = values.to_vec();
+        assert!(cur.iter().all(|x| fits(*x, width)), "value does not fit in {} bits", width);
+
+        let mut next_zeros = Vec::with_capacity(len);
+        let mut next_ones = Vec::with_capacity(len);
+        for level in 0..width {
+            let bit = width - 1 - level;
+            let mut words: Vec<BitCell> = vec![0; len.div_ceil(BIT_CELL_SIZE)];
+
+            for (i, x) in cur.iter().enumerate() {
+                if (x >> bit) & 1 == 1 {
+                    words[i / BIT_CELL_SIZE] |= (1 as BitCell) << (i % BIT_CELL_SIZE);
+                    next_ones.push(*x);
+                } else {
+                    next_zeros.push(*x);
+                }
+            }
+
+            zeros.push(next_zeros.len());
+            levels.push(FastRASBVec::new(BitVector::from_words(words, len)));
+
+            cur.clear();
+            cur.append(&mut next_zeros);
+            cur.append(&mut next_ones);
+        }
+
+        WaveletMatrix {
+            levels,
+            zeros,
+            width,
+            len,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    // Position of `i` on the next level, after following a `bit` on `level`.
+    fn next_pos(&self, level: usize, i: usize, bit: u32) -> usize {
+        let ones = self.levels[level].rank(i);
+        if bit == 0 {
+            i - ones
+        } else {
+            self.zeros[level] + ones
+        }
+    }
+
+    // Get the i'th value of the sequence.
+    pub fn access(&self, mut i: usize) -> u64 {
+        assert!(i < self.len);
+
+        let mut value = 0;
+        for level in 0..self.width {
+            let bit = self.levels[level].access(i);
+            value = (value << 1) | bit as u64;
+            i = self.next_pos(level, i, bit);
+        }
+        value
+    }
+
+    // Number of occurrences of `c` in [0, i).
+    pub fn rank(&self, c: u64, i: usize) -> usize {
+        assert!(i <= self.len);
+        if !fits(c, self.width) {
+            return 0;
+        }
+
+        let (mut l, mut r) = (0, i);
+        for level in 0..self.width {
+            let bit = ((c >> (self.width - 1 - level)) & 1) as u32;
+            l = self.next_pos(level, l, bit);
+            r = self.next_pos(level, r, bit);
+        }
+        r - l
+    }
+
+    // Position of the k'th occurrence of `c` (1-based, like select1 on bit vectors), or None if `c`
+    // occurs less than k times.
+    pub fn select(&self, c: u64, k: usize) -> Option<usize> {
+        if k == 0 || !fits(c, self.width) {
+            return None;
+        }
+
+        // All occurrences of c are next to each other on the last level, find where they start.
+        let (mut l, mut r) = (0, self.len);
+        for level in 0..self.width {
+            let bit = ((c >> (self.width - 1 - level)) & 1) as u32;
+            l = self.next_pos(level, l, bit);
+            r = self.next_pos(level, r, bit);
+        }
+
+        if r - l < k {
+            return None;
+        }
+
+        // Follow the k'th occurrence back up to the first level.
+        let mut pos = l + k - 1;
+        for level in (0..self.width).rev() {
+            let bit = (c >> (self.width - 1 - level)) & 1;
+            pos = if bit == 0 {
+                self.levels[level].select0(pos + 1)
+            } else {
+                self.levels[level].select1(pos - self.zeros[level] + 1)
+            }.unwrap();
+        }
+        Some(pos)
+    }
+
+    // The k'th smallest value (0-based) in [l, r), or None if there are at most k values in the range.
+    pub fn quantile(&self, mut l: usize, mut r: usize, mut k: usize) -> Option<u64> {
+        assert!(l <= r && r <= self.len);
+        if k >= r - l {
+            return None;
+        }
+
+        let mut value = 0;
+        for level in 0..self.width {
+            let (zl, zr) = (self.next_pos(level, l, 0), self.next_pos(level, r, 0));
+            let nr_zeros = zr - zl;
+
+            if k < nr_zeros {
+                value <<= 1;
+                (l, r) = (zl, zr);
+            } else {
+                value = (value << 1) | 1;
+                k -= nr_zeros;
+                (l, r) = (self.zeros[level] + (l - zl), self.zeros[level] + (r - zr));
+            }
+        }
+        Some(value)
+    }
+
+    // Number of values in [l, r) which are smaller than `x`.
+    fn count_less(&self, mut l: usize, mut r: usize, x: u64) -> usize {
+        if !fits(x, self.width) {
+            return r - l;
+        }
+
+        let mut count = 0;
+        for level in 0..self.width {
+            let bit = ((x >> (self.width - 1 - level)) & 1) as u32;
+            if bit == 1 {
+                // All values with a 0 bit here are smaller than x.
+                count += (r - l) - (self.levels[level].rank(r) - self.levels[level].rank(l));
+            }
+            l = self.next_pos(level, l, bit);
+            r = self.next_pos(level, r, bit);
+        }
+        count
+    }
+
+    // Number of values v in [l, r) with lo <= v < hi.
+    pub fn range_freq(&self, l: usize, r: usize, lo: u64, hi: u64) -> usize {
+        assert!(l <= r && r <= self.len);
+        if lo >= hi {
+            return 0;
+        }
+        self.count_less(l, r, hi) - self.count_less(l, r, lo)
+    }
+
+    // Memory used by all levels, including their bits.
+    pub fn get_memory_usage(&self) -> usize {
+        self.levels.iter().map(|b| b.bits().get_memory_usage() + b.get_memory_usage()).sum::<usize>() +
+            self.zeros.len() * std::mem::size_of::<usize>()
+    }
+}
+
+// Whether `x` can be stored in `width` bits.
+fn fits(x: u64, width: usize) -> bool {
+    width >= 64 || x >> width == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fast_bvec::SmallRASB;
+    use rand::{Rng, SeedableRng};
+    use rand_xoshiro::Xoshiro256Plus;
+
+    fn check(values: &[u64], width: usize, seed: u64) {
+        let wm = WaveletMatrix::<SmallRASB>::new(values, width);
+        let mut rng = Xoshiro256Plus::seed_from_u64(seed);
+        let n = values.len();
+        let random_symbol = |rng: &mut Xoshiro256Plus| {
+            if n > 0 && rng.gen_bool(0.8) { values[rng.gen_range(0..n)] } else { rng.gen::<u64>() >> (64 - width) }
+        };
+
+        for i in 0..n {
+            assert_eq!(wm.access(i), values[i]);
+        }
+
+        for _ in 0..300 {
+            let c = random_symbol(&mut rng);
+            let i = rng.gen_range(0..=n);
+            assert_eq!(wm.rank(c, i), values[..i].iter().filter(|x| **x == c).count());
+
+            let k = rng.gen_range(0..5);
+            let expected = values.iter().enumerate().filter(|(_, x)| **x == c).map(|(i, _)| i).nth(k.max(1) - 1);
+            assert_eq!(wm.select(c, k), if k == 0 { None } else { expected });
+
+            let l = rng.gen_range(0..=n);
+            let r = rng.gen_range(l..=n);
+            let mut sorted = values[l..r].to_vec();
+            sorted.sort();
+            let k = rng.gen_range(0..=sorted.len());
+            assert_eq!(wm.quantile(l, r, k), sorted.get(k).copied());
+
+            let (a, b) = (random_symbol(&mut rng), random_symbol(&mut rng));
+            let (lo, hi) = (a.min(b), a.max(b));
+            assert_eq!(wm.range_freq(l, r, lo, hi), sorted.iter().filter(|x| lo <= **x && **x < hi).count());
+        }
+    }
+
+    #[test]
+    fn random() {
+        let mut rng = Xoshiro256Plus::seed_from_u64(42);
+        for (n, width) in [(0, 4), (1, 1), (100, 1), (500, 5), (300, 17), (200, 64)] {
+            let values = (0..n).map(|_| if width == 64 { rng.gen::<u64>() } else { rng.gen_range(0..1u64 << width) }).collect::<Vec<_>>();
+            check(&values, width, n as u64);
+        }
+    }
+
+    #[test]
+    fn sample() {
+        let values = [5, 3, 5, 0, 7, 3, 5];
+        let wm = WaveletMatrix::<DefaultParams>::new(&values, 3);
+        assert_eq!(wm.rank(5, 7), 3);
+        assert_eq!(wm.select(3, 2), Some(5));
+        assert_eq!(wm.select(3, 3), None);
+        assert_eq!(wm.quantile(1, 5, 0), Some(0));
+        assert_eq!(wm.quantile(1, 5, 3), Some(7));
+        assert_eq!(wm.range_freq(0, 7, 3, 6), 5);
+    }
+}